@@ -1,6 +1,7 @@
+use super::{commit::CommitCertificate, consensus::ConsensusParams};
 use crate::{
     types::blockchain::Transaction,
-    utils::hasher::{block_hasher, transactions_hasher},
+    utils::hasher::{block_hasher, merkle_root},
 };
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -18,33 +19,88 @@ pub struct Block {
     pub transactions: Vec<Transaction>,
 
     pub merkle_root: Option<String>,
+
+    // id of the validator whose turn it was to produce the block, empty for genesis
+    #[serde(default)]
+    pub producer: String,
+
+    // round of the commit protocol the block was proposed in, together with the height it
+    // decides whose turn it was
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub round: u32,
+
+    // the producer's signature over the block header, not part of the hash. Empty for genesis.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub signature: String,
+
+    // how proposals are decided on this chain, only set on the genesis block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consensus: Option<ConsensusParams>,
+
+    // the precommits that made the block final, not part of the hash. Unset on genesis and on a
+    // block that is only proposed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<CommitCertificate>,
+}
+
+fn is_zero(round: &u32) -> bool {
+    *round == 0
 }
 
 impl Block {
-    pub fn init() -> Self {
-        let mut genesis_block = Block {
+    pub fn init(genesis_transactions: Vec<Transaction>, consensus: ConsensusParams) -> Self {
+        // every node builds the same genesis block from the same genesis file, so the chains of
+        // different nodes can be linked up block by block
+        Block {
             index: 0,
             prev_hash: "0".to_string(),
             hash: None,
-            timestamp: Utc::now(),
-            transactions: Vec::new(),
+            timestamp: DateTime::UNIX_EPOCH,
+            transactions: genesis_transactions,
             merkle_root: None,
-        };
-        genesis_block.hash = Some(block_hasher(&genesis_block));
-        genesis_block.merkle_root = Some(transactions_hasher(&genesis_block.transactions));
-
-        genesis_block
+            producer: String::new(),
+            round: 0,
+            signature: String::new(),
+            consensus: Some(consensus),
+            commit: None,
+        }
+        .sealed()
     }
 
-    pub fn new(index: u32, prev_hash: String, current_transaction: Vec<Transaction>) -> Self {
+    pub fn new(
+        index: u32,
+        round: u32,
+        prev_hash: String,
+        producer: String,
+        current_transaction: Vec<Transaction>,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
         Self {
             index,
             prev_hash,
             hash: None,
-            timestamp: Utc::now(),
+            timestamp,
             transactions: current_transaction,
             merkle_root: None,
+            producer,
+            round,
+            signature: String::new(),
+            consensus: None,
+            commit: None,
         }
+        .sealed()
+    }
+
+    // Fills in the merkle root and then the hash, which covers it
+    fn sealed(mut self) -> Self {
+        self.merkle_root = Some(merkle_root(&self.transactions));
+        self.hash = Some(self.compute_hash());
+        self
+    }
+
+    // The hash the block should carry, the same function is used to seal and to verify
+    pub fn compute_hash(&self) -> String {
+        block_hasher(self)
     }
 
     // pub async fn get_block_after_timestamp(&self, timestamp: Datetime<Utc>) -> Block {
@@ -0,0 +1,106 @@
+use super::{block::Block, registry::AgentRegistry};
+use crate::utils::crypto::verify_vote;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VoteKind {
+    Prevote,
+
+    Precommit,
+}
+
+// A validator's vote in one round of the commit protocol
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BlockVote {
+    pub kind: VoteKind,
+
+    pub height: u32,
+
+    pub round: u32,
+
+    // hash of the block voted for, `None` votes for no block in this round
+    pub block_hash: Option<String>,
+
+    pub validator: String,
+
+    // hex encoded ed25519 signature over everything above, with the validator's registered key
+    pub signature: String,
+}
+
+// A block offered for its height in a round. `valid_round` is set when the proposer offers a
+// block again that already got prevotes from more than 2/3 of the validators in that round, the
+// block then keeps the round it was first proposed in.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockProposal {
+    pub round: u32,
+
+    pub valid_round: Option<u32>,
+
+    pub block: Block,
+}
+
+// Proof that a block is final: precommits for it from more than 2/3 of the validators, all cast
+// in the same round
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CommitCertificate {
+    pub round: u32,
+
+    pub precommits: Vec<BlockVote>,
+}
+
+// Smallest number of votes that is more than 2/3 of `validators`
+pub fn quorum(validators: usize) -> usize {
+    validators * 2 / 3 + 1
+}
+
+// Checks that `commit` makes `block` final, with `registry` built from the blocks before it
+pub fn check_commit(
+    block: &Block,
+    commit: &CommitCertificate,
+    registry: &AgentRegistry,
+) -> Result<(), String> {
+    let validators = registry.validators();
+
+    let mut signers = HashSet::new();
+    for vote in &commit.precommits {
+        if vote.kind != VoteKind::Precommit
+            || vote.height != block.index
+            || vote.round != commit.round
+            || vote.block_hash != block.hash
+        {
+            return Err(format!(
+                "Vote of {} is not a precommit for block {} in round {}",
+                vote.validator, block.index, commit.round
+            ));
+        }
+
+        let Some(record) = validators
+            .iter()
+            .find(|record| record.agent_id == vote.validator)
+        else {
+            return Err(format!("{} is not a validator", vote.validator));
+        };
+        verify_vote(vote, &record.public_key).map_err(|e| e.reason().to_string())?;
+
+        if !signers.insert(vote.validator.as_str()) {
+            return Err(format!(
+                "{} precommitted block {} twice",
+                vote.validator, block.index
+            ));
+        }
+    }
+
+    let needed = quorum(validators.len());
+    if signers.len() < needed {
+        return Err(format!(
+            "Block {} has {} precommit(s), {} of {} validators are needed",
+            block.index,
+            signers.len(),
+            needed,
+            validators.len()
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use super::init::VoteTally;
+use crate::types::blockchain::ActionType;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+// An exact ratio such as "2/3", compared with integer arithmetic so that 2 out of 3 really is 2/3
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Fraction {
+    pub numerator: u32,
+
+    pub denominator: u32,
+}
+
+impl Fraction {
+    pub const fn new(numerator: u32, denominator: u32) -> Fraction {
+        Fraction {
+            numerator,
+            denominator,
+        }
+    }
+
+    // Whether `part` out of `whole` is at least this fraction
+    pub fn reached(&self, part: usize, whole: usize) -> bool {
+        part as u64 * self.denominator as u64 >= whole as u64 * self.numerator as u64
+    }
+}
+
+impl TryFrom<String> for Fraction {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (numerator, denominator) = value
+            .split_once('/')
+            .and_then(|(n, d)| Some((n.trim().parse().ok()?, d.trim().parse().ok()?)))
+            .ok_or_else(|| format!("Expected a fraction like \"2/3\", got {:?}", value))?;
+
+        if denominator == 0 || numerator == 0 || numerator > denominator {
+            return Err(format!("Fraction {:?} must be between 0 and 1", value));
+        }
+
+        Ok(Fraction::new(numerator, denominator))
+    }
+}
+
+impl FromStr for Fraction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Fraction::try_from(s.to_string())
+    }
+}
+
+impl From<Fraction> for String {
+    fn from(fraction: Fraction) -> String {
+        fraction.to_string()
+    }
+}
+
+impl fmt::Display for Fraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+// How a proposal is decided. The electorate is every registered agent whose role lets it vote.
+// Fixed in the genesis block so that every node reaches the same verdict.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsensusParams {
+    // share of the electorate that has to vote before a proposal can be decided
+    pub quorum: Fraction,
+
+    // share of the votes cast that has to accept
+    pub accept_threshold: Fraction,
+
+    // share of the votes cast that has to reject
+    pub reject_threshold: Fraction,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams {
+            quorum: Fraction::new(2, 3),
+            accept_threshold: Fraction::new(2, 3),
+            reject_threshold: Fraction::new(2, 3),
+        }
+    }
+}
+
+impl ConsensusParams {
+    pub fn verdict(&self, tally: &VoteTally, electorate: usize) -> Option<ActionType> {
+        let cast = tally.accept + tally.reject;
+        if electorate == 0 || cast == 0 || !self.quorum.reached(cast, electorate) {
+            return None;
+        }
+
+        if self.accept_threshold.reached(tally.accept, cast) {
+            Some(ActionType::VoteAccept)
+        } else if self.reject_threshold.reached(tally.reject, cast) {
+            Some(ActionType::VoteReject)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use super::block::Block;
+use crate::{types::blockchain::ActionType, utils::hasher::transaction_hash};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TxLocation {
+    pub block_index: u32,
+
+    pub tx_index: usize,
+}
+
+// Where every transaction in the chain sits, by id, by proposal and by sender, so queries do not
+// have to scan the blocks. Derived from the blocks and kept up to date as they are appended.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChainIndex {
+    pub by_id: HashMap<String, TxLocation>,
+
+    // keyed by reasoning hash, every transaction of the proposal in chain order, registrations
+    // belong to no proposal
+    pub by_proposal: HashMap<String, Vec<TxLocation>>,
+
+    // keyed by agent id, every transaction the agent sent in chain order
+    pub by_agent: HashMap<String, Vec<TxLocation>>,
+
+    // keyed by agent id, the highest nonce the agent used. Nonces have to increase along the
+    // chain, anything at or below it is a replay.
+    pub last_nonces: HashMap<String, u64>,
+}
+
+impl ChainIndex {
+    pub fn from_blocks<'a>(blocks: impl IntoIterator<Item = &'a Block>) -> ChainIndex {
+        let mut index = ChainIndex::default();
+        for block in blocks {
+            index.apply_block(block);
+        }
+
+        index
+    }
+
+    pub fn apply_block(&mut self, block: &Block) {
+        for (tx_index, tx) in block.transactions.iter().enumerate() {
+            let location = TxLocation {
+                block_index: block.index,
+                tx_index,
+            };
+
+            self.by_id.insert(transaction_hash(tx), location);
+            if tx.action_type != ActionType::RegisterAgent {
+                self.by_proposal
+                    .entry(tx.reasoning_hash.clone())
+                    .or_default()
+                    .push(location);
+            }
+            self.by_agent
+                .entry(tx.agent_id.clone())
+                .or_default()
+                .push(location);
+            let last = self.last_nonces.entry(tx.agent_id.clone()).or_default();
+            *last = (*last).max(tx.nonce);
+        }
+    }
+
+    pub fn get(&self, tx_hash: &str) -> Option<TxLocation> {
+        self.by_id.get(tx_hash).copied()
+    }
+
+    pub fn proposal(&self, reasoning_hash: &str) -> &[TxLocation] {
+        self.by_proposal
+            .get(reasoning_hash)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn agent(&self, agent_id: &str) -> &[TxLocation] {
+        self.by_agent
+            .get(agent_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    // Highest nonce of the agent in the chain, `None` if it never sent a transaction
+    pub fn last_nonce(&self, agent_id: &str) -> Option<u64> {
+        self.last_nonces.get(agent_id).copied()
+    }
+}
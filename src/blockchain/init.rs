@@ -1,65 +1,388 @@
-use super::block::Block;
+use super::{
+    block::Block,
+    consensus::ConsensusParams,
+    index::{ChainIndex, TxLocation},
+    mempool::Mempool,
+    registry::AgentRegistry,
+    store::BlockStore,
+    verify::{check_block, check_contents},
+};
 use crate::{
-    p2p::CURRENT_TRANSACTIONS,
-    types::blockchain::{ActionType, Transaction},
-    utils::hasher::{block_hasher, transactions_hasher},
+    types::{
+        blockchain::{ActionType, Transaction, TransactionMessage},
+        error::ErrorTypes,
+    },
+    utils::{
+        crypto::{sign_block, verify_transaction, KeyPair},
+        hasher::{merkle_proof, MerkleProof},
+    },
 };
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::LinkedList,
+    collections::HashMap,
     sync::{Arc, Mutex},
-    thread::AccessError,
 };
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct VoteTally {
+    pub accept: usize,
+
+    pub reject: usize,
+}
+
+fn is_vote(tx: &Transaction) -> bool {
+    matches!(
+        tx.action_type,
+        ActionType::VoteAccept | ActionType::VoteReject
+    )
+}
+
+// The vote of `agent_id` on `reasoning_hash` that currently counts: with nonces only ever going
+// up, a vote change always carries a higher nonce than the vote it replaces
+pub fn active_vote<'a>(
+    transactions: impl IntoIterator<Item = &'a Transaction>,
+    agent_id: &str,
+    reasoning_hash: &str,
+) -> Option<&'a Transaction> {
+    transactions
+        .into_iter()
+        .filter(|tx| is_vote(tx) && tx.agent_id == agent_id && tx.reasoning_hash == reasoning_hash)
+        .max_by_key(|tx| tx.nonce)
+}
+
+// Active votes in `transactions` grouped by the proposal (reasoning hash) they were cast on, an
+// agent counts once per proposal no matter how often it changed its vote
+pub fn tally_votes<'a>(
+    transactions: impl IntoIterator<Item = &'a Transaction>,
+) -> HashMap<String, VoteTally> {
+    let mut active: HashMap<(&str, &str), &Transaction> = HashMap::new();
+    for tx in transactions.into_iter().filter(|tx| is_vote(tx)) {
+        let key = (tx.reasoning_hash.as_str(), tx.agent_id.as_str());
+        if active
+            .get(&key)
+            .is_none_or(|current| current.nonce < tx.nonce)
+        {
+            active.insert(key, tx);
+        }
+    }
+
+    let mut tallies: HashMap<String, VoteTally> = HashMap::new();
+    for tx in active.into_values() {
+        let tally = tallies.entry(tx.reasoning_hash.clone()).or_default();
+        if tx.action_type == ActionType::VoteAccept {
+            tally.accept += 1;
+        } else {
+            tally.reject += 1;
+        }
+    }
+
+    tallies
+}
+
+// A transaction in the chain together with what a light client needs to check its inclusion
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TransactionProof {
+    pub block_index: u32,
+
+    pub block_hash: Option<String>,
+
+    pub merkle_root: Option<String>,
+
+    pub transaction: Transaction,
+
+    pub proof: MerkleProof,
+}
+
+// Whether a pending transaction goes into the block finalizing `reasoning_hash`: the proposal
+// itself, its evaluations and votes, plus pending registrations which have no proposal of
+// their own to ride along with
+fn belongs_to_block(tx: &Transaction, reasoning_hash: &str) -> bool {
+    tx.reasoning_hash == reasoning_hash || tx.action_type == ActionType::RegisterAgent
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Blockchain {
-    pub blocks: LinkedList<Block>,
+    // `blocks[i].index == i`, from genesis up
+    pub blocks: Vec<Block>,
 
     pub current_transactions: Vec<Transaction>,
 
     pub archieved_transactions: Vec<Transaction>,
+
+    // derived from the blocks above, kept up to date as blocks are appended
+    pub registry: AgentRegistry,
+
+    pub index: ChainIndex,
+
+    // where blocks are persisted, `None` keeps the chain in memory only
+    #[serde(skip)]
+    pub store: Option<Arc<Mutex<BlockStore>>>,
 }
 
 impl Blockchain {
-    pub fn init() -> Blockchain {
-        let genesis_block = Block::init();
+    pub fn init(genesis_transactions: Vec<Transaction>, consensus: ConsensusParams) -> Blockchain {
+        Blockchain::from_blocks([Block::init(genesis_transactions, consensus)])
+    }
 
-        let mut blocks = LinkedList::new();
-        blocks.push_back(genesis_block);
+    // Loads the chain stored in `data_dir`, writing a fresh genesis block if there is none yet.
+    // `consensus` only goes into a new genesis block, a stored chain keeps the params it has.
+    pub fn open(
+        data_dir: &str,
+        genesis_transactions: Vec<Transaction>,
+        consensus: ConsensusParams,
+    ) -> Result<Blockchain, ErrorTypes> {
+        let (mut store, stored_blocks) = BlockStore::open(data_dir).map_err(|e| {
+            ErrorTypes::StorageError(format!(
+                "Error while opening block store in {}: {:?}",
+                data_dir, e
+            ))
+        })?;
 
-        Blockchain {
-            blocks,
-            current_transactions: Vec::new(),
-            archieved_transactions: Vec::new(),
+        let blocks: Vec<Block> = if stored_blocks.is_empty() {
+            let genesis_block = Block::init(genesis_transactions, consensus);
+            store.append(&genesis_block).map_err(|e| {
+                ErrorTypes::StorageError(format!("Error while storing genesis block: {:?}", e))
+            })?;
+            vec![genesis_block]
+        } else {
+            log::info!("Loaded {} block(s) from {}", stored_blocks.len(), data_dir);
+            stored_blocks
+        };
+        let mut blockchain = Blockchain::from_blocks(blocks);
+        blockchain.store = Some(Arc::new(Mutex::new(store)));
+
+        if blockchain.consensus_params() != consensus {
+            log::warn!(
+                "Consensus params in the config differ from the ones in genesis, using {:?}",
+                blockchain.consensus_params()
+            );
         }
+
+        Ok(blockchain)
+    }
+
+    // Reads the agents the chain starts out with: a JSON array of signed `RegisterAgent`
+    // transaction messages, as printed by the `sign` command. Anything else is dropped.
+    pub fn load_genesis_transactions(path: &str) -> Vec<Transaction> {
+        let messages: Vec<TransactionMessage> = match std::fs::read_to_string(path) {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    log::error!("Error while parsing genesis file {}: {:?}", path, e);
+                    return Vec::new();
+                }
+            },
+            Err(e) => {
+                log::warn!(
+                    "No genesis file at {}, starting without agents: {:?}",
+                    path,
+                    e
+                );
+                return Vec::new();
+            }
+        };
+
+        let mut transactions: Vec<Transaction> = Vec::new();
+        for tx in messages.into_iter().map(|message| message.payload) {
+            let duplicate = transactions
+                .iter()
+                .any(|registered| registered.agent_id == tx.agent_id);
+
+            if tx.action_type != ActionType::RegisterAgent
+                || tx.payload.agent_registration.is_none()
+                || duplicate
+                || verify_transaction(&tx).is_err()
+            {
+                log::warn!("Skipping invalid genesis transaction from {}", tx.agent_id);
+                continue;
+            }
+
+            transactions.push(tx);
+        }
+
+        transactions
     }
 
-    pub async fn add_new_block(&mut self) -> (Block, Blockchain) {
-        println!("\nBlockchain: {:?}\n", self);
-        let index = self.get_last_block().map(|block| block.index).unwrap_or(1) + 1;
-        println!("Last Block: {:?}", self.get_last_block());
+    // Builds the block finalizing the accepted proposal `reasoning_hash` from the node's
+    // mempool, to be offered in `round` of the commit protocol. `keypair` has to be the key of
+    // the validator whose turn it is, the block is signed with it. The block only becomes part of
+    // the chain once it is committed, see `append_block`.
+    pub fn propose_block(
+        &self,
+        mempool: &Mempool,
+        reasoning_hash: &str,
+        keypair: &KeyPair,
+        round: u32,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Block, ErrorTypes> {
+        let index = self.height() + 1;
+        let producer = match self.registry.producer(index, round) {
+            Some(producer) if producer.public_key == keypair.public_key => {
+                producer.agent_id.clone()
+            }
+            _ => {
+                return Err(ErrorTypes::Unauthorized(format!(
+                    "Key {} is not the one of the producer of block {} in round {}",
+                    keypair.public_key, index, round
+                )))
+            }
+        };
 
         let prev_hash = self
             .get_last_block()
             .and_then(|block| block.hash.clone())
             .unwrap_or_else(|| "0".to_string());
-        let mut global_tx = CURRENT_TRANSACTIONS.lock().await;
-        let mut block = Block::new(index, prev_hash, global_tx.to_vec());
-        global_tx.clear();
+        let transactions = mempool
+            .iter()
+            .filter(|tx| belongs_to_block(tx, reasoning_hash))
+            .cloned()
+            .collect();
+        let mut block = Block::new(index, round, prev_hash, producer, transactions, timestamp);
+        sign_block(&mut block, keypair)?;
+
+        Ok(block)
+    }
 
-        block.hash = Some(block_hasher(&block));
-        block.merkle_root = Some(transactions_hasher(&block.transactions));
+    // Checks a block proposed as our next one before voting for it: everything but the commit
+    // certificate, and every proposal it finalizes has to be accepted by the votes it carries
+    pub fn validate_proposed_block(&self, block: &Block) -> Result<(), ErrorTypes> {
+        check_contents(self.blocks.last(), block, &self.registry, &self.index).map_err(
+            |failure| {
+                ErrorTypes::InvalidBlock(format!(
+                    "Block {} failed {:?}: {}",
+                    failure.height, failure.kind, failure.reason
+                ))
+            },
+        )?;
 
-        self.blocks.push_back(block.clone());
-        self.current_transactions = Vec::new();
-        self.archieved_transactions
-            .append(&mut self.current_transactions);
-        (block, self.clone())
+        let mut tallies = tally_votes(&block.transactions);
+        for tx in block
+            .transactions
+            .iter()
+            .filter(|tx| tx.action_type == ActionType::ProposeUpdate)
+        {
+            let tally = tallies.remove(&tx.reasoning_hash).unwrap_or_default();
+            if self
+                .consensus_params()
+                .verdict(&tally, self.registry.electorate())
+                != Some(ActionType::VoteAccept)
+            {
+                return Err(ErrorTypes::InvalidBlock(format!(
+                    "Block {} finalizes proposal {} without the votes accepting it",
+                    block.index, tx.reasoning_hash
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Persists the block and makes it the new tip of the chain
+    fn commit_block(&mut self, block: &Block) -> Result<(), ErrorTypes> {
+        if let Some(store) = &self.store {
+            store.lock().unwrap().append(block).map_err(|e| {
+                ErrorTypes::StorageError(format!(
+                    "Error while storing block {}: {:?}",
+                    block.index, e
+                ))
+            })?;
+        }
+
+        self.registry.apply_block(block);
+        self.index.apply_block(block);
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    // Checks that a block received from a peer extends our chain, see `verify::check_block`
+    pub fn validate_next_block(&self, block: &Block) -> Result<(), ErrorTypes> {
+        check_block(self.blocks.last(), block, &self.registry, &self.index).map_err(|failure| {
+            ErrorTypes::InvalidBlock(format!(
+                "Block {} failed {:?}: {}",
+                failure.height, failure.kind, failure.reason
+            ))
+        })
+    }
+
+    // Builds an in-memory chain from blocks read back from a store
+    pub fn from_blocks(blocks: impl IntoIterator<Item = Block>) -> Blockchain {
+        let blocks: Vec<Block> = blocks.into_iter().collect();
+        let registry = AgentRegistry::from_blocks(&blocks);
+        let index = ChainIndex::from_blocks(&blocks);
+
+        Blockchain {
+            blocks,
+            current_transactions: Vec::new(),
+            archieved_transactions: Vec::new(),
+            registry,
+            index,
+            store: None,
+        }
+    }
+
+    // Appends a committed block, dropping its transactions from our mempool. On failure the
+    // mempool is left untouched.
+    pub fn append_block(&mut self, block: Block, mempool: &mut Mempool) -> Result<(), ErrorTypes> {
+        self.validate_next_block(&block)?;
+        self.commit_block(&block)?;
+        mempool.remove_included(&block);
+
+        log::info!("Appended block {}", block.index);
+        Ok(())
+    }
+
+    pub fn get_block(&self, height: u32) -> Option<&Block> {
+        self.blocks.get(height as usize)
+    }
+
+    pub fn get_block_by_hash(&self, hash: &str) -> Option<&Block> {
+        self.blocks
+            .iter()
+            .find(|block| block.hash.as_deref() == Some(hash))
+    }
+
+    // The block holding the transaction with the given id and its position in that block
+    pub fn find_transaction(&self, tx_hash: &str) -> Option<(&Block, usize)> {
+        let location = self.index.get(tx_hash)?;
+        Some((self.get_block(location.block_index)?, location.tx_index))
+    }
+
+    pub fn transaction_at(&self, location: TxLocation) -> Option<&Transaction> {
+        self.get_block(location.block_index)?
+            .transactions
+            .get(location.tx_index)
+    }
+
+    // Locates the transaction with the given id and proves it is part of its block
+    pub fn transaction_proof(&self, tx_hash: &str) -> Option<TransactionProof> {
+        let (block, tx_index) = self.find_transaction(tx_hash)?;
+
+        Some(TransactionProof {
+            block_index: block.index,
+            block_hash: block.hash.clone(),
+            merkle_root: block.merkle_root.clone(),
+            transaction: block.transactions[tx_index].clone(),
+            proof: merkle_proof(block, tx_index)?,
+        })
+    }
+
+    // Blocks with a height in `from..=to`, in height order
+    pub fn blocks_in_range(&self, from: u32, to: u32) -> Vec<Block> {
+        self.blocks
+            .iter()
+            .skip(from as usize)
+            .take_while(|block| block.index <= to)
+            .cloned()
+            .collect()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.blocks.last().map(|block| block.index).unwrap_or(0)
     }
 
     pub fn get_last_block(&self) -> Option<Block> {
-        self.blocks.back().cloned()
+        self.blocks.last().cloned()
     }
 
     pub fn get_blockchain_after_timestamp(&self, timestamp: DateTime<Utc>) -> Vec<Block> {
@@ -70,37 +393,30 @@ impl Blockchain {
             .collect()
     }
 
-    pub async fn proof_of_work(&self, connection_len: u32) -> Option<ActionType> {
-        let transactions = CURRENT_TRANSACTIONS.lock().await;
-
-        let accept_votes = transactions
-            .iter()
-            .filter(|tx| tx.action_type == ActionType::VoteAccept)
-            .count();
-        println!("Accept: {:?}", accept_votes);
-        let reject_votes = transactions
-            .iter()
-            .filter(|tx| tx.action_type == ActionType::VoteReject)
-            .count();
-        println!("Reject: {:?}", reject_votes);
-        let connection_len = connection_len - 1;
+    // Chains written before the params were recorded in genesis use the defaults
+    pub fn consensus_params(&self) -> ConsensusParams {
+        self.blocks
+            .first()
+            .and_then(|genesis| genesis.consensus)
+            .unwrap_or_default()
+    }
 
-        let accept_ratio = accept_votes as f32 / connection_len as f32;
-        let reject_ratio = reject_votes as f32 / connection_len as f32;
+    // Decides a proposal once enough of the registered voters have voted, counting the votes
+    // in `mempool`. Every node holding the same chain and votes reaches the same verdict.
+    pub fn decide_proposal(&self, mempool: &Mempool, reasoning_hash: &str) -> Option<ActionType> {
+        let tally = tally_votes(mempool.proposal(reasoning_hash))
+            .remove(reasoning_hash)
+            .unwrap_or_default();
+        let electorate = self.registry.electorate();
 
-        log::info!("Votes: Accept={} Reject={}", accept_votes, reject_votes);
         log::info!(
-            "Consensus ratio: Accept={:.2} Reject={:.2}",
-            accept_ratio,
-            reject_ratio
+            "Votes on {}: Accept={} Reject={} Electorate={}",
+            reasoning_hash,
+            tally.accept,
+            tally.reject,
+            electorate
         );
 
-        if accept_ratio >= 2.0 / 3.0 {
-            Some(ActionType::VoteAccept)
-        } else if reject_ratio >= 2.0 / 3.0 {
-            Some(ActionType::VoteReject)
-        } else {
-            None
-        }
+        self.consensus_params().verdict(&tally, electorate)
     }
 }
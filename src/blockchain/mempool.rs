@@ -0,0 +1,348 @@
+use super::{block::Block, init::Blockchain};
+use crate::{
+    types::{
+        blockchain::{ActionType, Transaction},
+        error::ErrorTypes,
+    },
+    utils::hasher::transaction_hash,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// How much the mempool holds before it starts dropping transactions
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MempoolLimits {
+    pub max_transactions: usize,
+
+    // a registration this old is dropped, a proposal once nothing happened on it for this long
+    pub max_age_secs: u64,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        MempoolLimits {
+            max_transactions: 10_000,
+            max_age_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    id: String,
+
+    tx: Transaction,
+
+    received_at: DateTime<Utc>,
+}
+
+// What goes once its time in the mempool is up
+#[derive(Clone, Debug)]
+enum Expiry {
+    // a registration, by id
+    Transaction(String),
+
+    // a proposal with everything pending on it, by reasoning hash
+    Proposal(String),
+}
+
+// Transactions received but not yet included in a block, indexed by id and by the proposal they
+// belong to. A proposal is kept or dropped as a whole, a vote without its proposal is of no use.
+#[derive(Clone, Debug, Default)]
+pub struct Mempool {
+    pub limits: MempoolLimits,
+
+    // keyed by arrival, so iterating and evicting go oldest first
+    entries: BTreeMap<u64, Entry>,
+
+    ids: HashMap<String, u64>,
+
+    // arrivals of the proposal, votes and evaluations, by reasoning hash
+    proposals: HashMap<String, BTreeSet<u64>>,
+
+    // nonces and arrivals of the transactions of each agent
+    nonces: HashMap<String, BTreeSet<(u64, u64)>>,
+
+    // keyed by the last arrival of a registration or proposal, so expiring goes oldest first
+    expiries: BTreeMap<(DateTime<Utc>, u64), Expiry>,
+
+    next_seq: u64,
+}
+
+fn is_proposal_activity(tx: &Transaction) -> bool {
+    matches!(
+        tx.action_type,
+        ActionType::ProposeUpdate
+            | ActionType::VoteAccept
+            | ActionType::VoteReject
+            | ActionType::EvaluateUpdate
+    )
+}
+
+impl Mempool {
+    pub fn new(limits: MempoolLimits) -> Mempool {
+        Mempool {
+            limits,
+            ..Mempool::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains_key(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Transaction> {
+        self.ids.get(id).map(|seq| &self.entries[seq].tx)
+    }
+
+    // Every pending transaction, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.values().map(|entry| &entry.tx)
+    }
+
+    // The pending proposal, votes and evaluations on `reasoning_hash`, oldest first
+    pub fn proposal(&self, reasoning_hash: &str) -> impl Iterator<Item = &Transaction> {
+        self.proposals
+            .get(reasoning_hash)
+            .into_iter()
+            .flatten()
+            .map(|seq| &self.entries[seq].tx)
+    }
+
+    pub fn last_nonce(&self, agent_id: &str) -> Option<u64> {
+        self.nonces
+            .get(agent_id)
+            .and_then(|nonces| nonces.last())
+            .map(|(nonce, _)| *nonce)
+    }
+
+    // Adds a transaction that passed validation. Stale transactions go first, then, if the
+    // mempool is still full, the oldest transaction and the rest of its proposal. Returns the
+    // proposals that were dropped to make room.
+    pub fn insert(
+        &mut self,
+        tx: Transaction,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, ErrorTypes> {
+        let id = transaction_hash(&tx);
+        if self.contains(&id) {
+            return Err(ErrorTypes::DuplicateTransaction(format!(
+                "Transaction {} is already pending",
+                id
+            )));
+        }
+
+        let mut dropped = self.expire(now);
+        // the entries before `from` all belong to the proposal of `tx`, they stay
+        let mut from = 0;
+        while self.len() >= self.limits.max_transactions {
+            // a proposal is never evicted to make room for its own votes
+            let oldest = self
+                .entries
+                .range(from..)
+                .find(|(_, entry)| {
+                    !is_proposal_activity(&tx)
+                        || !is_proposal_activity(&entry.tx)
+                        || entry.tx.reasoning_hash != tx.reasoning_hash
+                })
+                .map(|(seq, entry)| (*seq, entry.id.clone(), entry.tx.clone()));
+            let Some((seq, oldest_id, oldest)) = oldest else {
+                return Err(ErrorTypes::MempoolFull(format!(
+                    "Mempool holds {} transactions, all on proposal {}",
+                    self.len(),
+                    tx.reasoning_hash
+                )));
+            };
+            from = seq;
+
+            if is_proposal_activity(&oldest) {
+                log::warn!("Mempool full, evicting proposal {}", oldest.reasoning_hash);
+                self.remove_proposal(&oldest.reasoning_hash);
+                dropped.push(oldest.reasoning_hash);
+            } else {
+                log::warn!("Mempool full, evicting transaction {}", oldest_id);
+                self.remove(&oldest_id);
+            }
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if is_proposal_activity(&tx) {
+            let seqs = self.proposals.entry(tx.reasoning_hash.clone()).or_default();
+            // the proposal now expires counting from this arrival
+            if let Some(last) = seqs.last() {
+                self.expiries
+                    .remove(&(self.entries[last].received_at, *last));
+            }
+            seqs.insert(seq);
+            self.expiries
+                .insert((now, seq), Expiry::Proposal(tx.reasoning_hash.clone()));
+        } else {
+            self.expiries
+                .insert((now, seq), Expiry::Transaction(id.clone()));
+        }
+        self.nonces
+            .entry(tx.agent_id.clone())
+            .or_default()
+            .insert((tx.nonce, seq));
+        self.ids.insert(id.clone(), seq);
+        self.entries.insert(
+            seq,
+            Entry {
+                id,
+                tx,
+                received_at: now,
+            },
+        );
+
+        Ok(dropped)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Transaction> {
+        let seq = self.ids.remove(id)?;
+        let entry = self.entries.remove(&seq)?;
+        self.expiries.remove(&(entry.received_at, seq));
+        if is_proposal_activity(&entry.tx)
+            && let Some(seqs) = self.proposals.get_mut(&entry.tx.reasoning_hash)
+        {
+            seqs.remove(&seq);
+            match seqs.last() {
+                // the last arrival left, the proposal expires counting from the one before
+                Some(last) if *last < seq => {
+                    self.expiries.insert(
+                        (self.entries[last].received_at, *last),
+                        Expiry::Proposal(entry.tx.reasoning_hash.clone()),
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    self.proposals.remove(&entry.tx.reasoning_hash);
+                }
+            }
+        }
+        if let Some(nonces) = self.nonces.get_mut(&entry.tx.agent_id) {
+            nonces.remove(&(entry.tx.nonce, seq));
+            if nonces.is_empty() {
+                self.nonces.remove(&entry.tx.agent_id);
+            }
+        }
+
+        Some(entry.tx)
+    }
+
+    // Drops everything pending on a proposal, returns how many transactions that was
+    pub fn remove_proposal(&mut self, reasoning_hash: &str) -> usize {
+        let ids: Vec<String> = self
+            .proposals
+            .get(reasoning_hash)
+            .into_iter()
+            .flatten()
+            .map(|seq| self.entries[seq].id.clone())
+            .collect();
+        for id in &ids {
+            self.remove(id);
+        }
+
+        ids.len()
+    }
+
+    // Drops the transactions a block included, everything else stays
+    pub fn remove_included(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            self.remove(&transaction_hash(tx));
+        }
+    }
+
+    // Drops registrations older than `max_age_secs` and proposals nothing happened on for as
+    // long. Returns the dropped proposals.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let cutoff = now - Duration::seconds(self.limits.max_age_secs as i64);
+
+        let mut idle = Vec::new();
+        while let Some(entry) = self.expiries.first_entry()
+            && entry.key().0 < cutoff
+        {
+            match entry.remove() {
+                Expiry::Transaction(id) => {
+                    log::info!("Transaction {} expired from the mempool", id);
+                    self.remove(&id);
+                }
+                Expiry::Proposal(reasoning_hash) => {
+                    log::info!("Proposal {} expired from the mempool", reasoning_hash);
+                    self.remove_proposal(&reasoning_hash);
+                    idle.push(reasoning_hash);
+                }
+            }
+        }
+
+        idle
+    }
+
+    // Checks what is left against the chain after a block was committed: nonces the chain has
+    // moved past, agents the registry no longer allows, registrations that already happened and
+    // proposals the chain already holds are dropped. Returns the dropped proposals.
+    pub fn revalidate(&mut self, blockchain: &Blockchain) -> Vec<String> {
+        let mut dropped = Vec::new();
+        let invalid: Vec<(String, Transaction, String)> = self
+            .entries
+            .values()
+            .filter_map(|entry| {
+                let reason = Self::invalid_after_commit(&entry.tx, blockchain)?;
+                Some((entry.id.clone(), entry.tx.clone(), reason))
+            })
+            .collect();
+
+        for (id, tx, reason) in invalid {
+            if !self.contains(&id) {
+                continue;
+            }
+            log::info!("Dropping transaction {} from the mempool: {}", id, reason);
+            if tx.action_type == ActionType::ProposeUpdate {
+                self.remove_proposal(&tx.reasoning_hash);
+                dropped.push(tx.reasoning_hash);
+            } else {
+                self.remove(&id);
+            }
+        }
+
+        dropped
+    }
+
+    fn invalid_after_commit(tx: &Transaction, blockchain: &Blockchain) -> Option<String> {
+        if let Some(last) = blockchain.index.last_nonce(&tx.agent_id)
+            && tx.nonce <= last
+        {
+            return Some(format!(
+                "nonce {} is not above {} in the chain",
+                tx.nonce, last
+            ));
+        }
+
+        let allowed = match tx.action_type {
+            ActionType::RegisterAgent => blockchain.registry.validate_registration(tx),
+            _ => blockchain.registry.authorize(tx).map(|_| ()),
+        };
+        if let Err(err) = allowed {
+            return Some(err.reason().to_string());
+        }
+
+        if is_proposal_activity(tx) && !blockchain.index.proposal(&tx.reasoning_hash).is_empty() {
+            return Some(format!(
+                "proposal {} is already in the chain",
+                tx.reasoning_hash
+            ));
+        }
+
+        None
+    }
+}
@@ -1,2 +1,10 @@
 pub mod block;
+pub mod commit;
+pub mod consensus;
+pub mod index;
 pub mod init;
+pub mod mempool;
+pub mod proposal;
+pub mod registry;
+pub mod store;
+pub mod verify;
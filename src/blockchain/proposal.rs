@@ -0,0 +1,138 @@
+use crate::types::{
+    blockchain::{Deadline, ProposalStatus, Transaction},
+    error::ErrorTypes,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+// Voting period for proposals that do not set their own deadline
+pub const DEFAULT_VOTING_PERIOD_HOURS: i64 = 24;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProposalState {
+    pub reasoning_hash: String,
+
+    pub owner: String,
+
+    pub status: ProposalStatus,
+
+    pub proposed_at: DateTime<Utc>,
+
+    // chain height when the proposal was received
+    pub proposed_at_height: u32,
+
+    pub deadline: Deadline,
+
+    // height of the block that finalized the proposal
+    pub finalized_in: Option<u32>,
+}
+
+fn can_transition(from: &ProposalStatus, to: &ProposalStatus) -> bool {
+    use ProposalStatus::*;
+
+    matches!(
+        (from, to),
+        (Proposed, UnderEvaluation)
+            | (Proposed, Voting)
+            | (UnderEvaluation, UnderEvaluation)
+            | (UnderEvaluation, Voting)
+            | (Voting, Voting)
+            | (Voting, Accepted)
+            | (Voting, Rejected)
+            | (Proposed, Expired)
+            | (UnderEvaluation, Expired)
+            | (Voting, Expired)
+            | (Accepted, Finalized)
+            // the votes accepting it were dropped before a block included them
+            | (Accepted, Voting)
+            | (Accepted, Rejected)
+            | (Accepted, Expired)
+    )
+}
+
+impl ProposalState {
+    pub fn new(tx: &Transaction, height: u32, now: DateTime<Utc>) -> Result<Self, ErrorTypes> {
+        let deadline =
+            tx.payload.voting_deadline.clone().unwrap_or_else(|| {
+                Deadline::Time(now + Duration::hours(DEFAULT_VOTING_PERIOD_HOURS))
+            });
+
+        let proposal = ProposalState {
+            reasoning_hash: tx.reasoning_hash.clone(),
+            owner: tx.agent_id.clone(),
+            status: ProposalStatus::Proposed,
+            proposed_at: now,
+            proposed_at_height: height,
+            deadline,
+            finalized_in: None,
+        };
+
+        if proposal.deadline_passed(height, now) {
+            return Err(ErrorTypes::InvalidProposal(format!(
+                "Voting deadline {:?} of proposal {} has already passed",
+                proposal.deadline, proposal.reasoning_hash
+            )));
+        }
+
+        Ok(proposal)
+    }
+
+    // A proposal that made it into a block before this node started
+    pub fn finalized(tx: &Transaction, block_index: u32, timestamp: DateTime<Utc>) -> Self {
+        ProposalState {
+            reasoning_hash: tx.reasoning_hash.clone(),
+            owner: tx.agent_id.clone(),
+            status: ProposalStatus::Finalized,
+            proposed_at: timestamp,
+            proposed_at_height: block_index,
+            deadline: Deadline::Height(block_index),
+            finalized_in: Some(block_index),
+        }
+    }
+
+    pub fn deadline_passed(&self, height: u32, now: DateTime<Utc>) -> bool {
+        match &self.deadline {
+            Deadline::Height(deadline) => height >= *deadline,
+            Deadline::Time(deadline) => now >= *deadline,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(
+            self.status,
+            ProposalStatus::Proposed | ProposalStatus::UnderEvaluation | ProposalStatus::Voting
+        )
+    }
+
+    pub fn transition(&mut self, next: ProposalStatus) -> Result<(), ErrorTypes> {
+        if !can_transition(&self.status, &next) {
+            return Err(ErrorTypes::InvalidTransition(format!(
+                "Proposal {} cannot move from {:?} to {:?}",
+                self.reasoning_hash, self.status, next
+            )));
+        }
+
+        if self.status != next {
+            log::info!(
+                "Proposal {}: {:?} -> {:?}",
+                self.reasoning_hash,
+                self.status,
+                next
+            );
+        }
+        self.status = next;
+
+        Ok(())
+    }
+
+    // Moves an open proposal past its deadline to `Expired`, returns whether it did
+    pub fn expire_if_due(&mut self, height: u32, now: DateTime<Utc>) -> bool {
+        if self.is_open() && self.deadline_passed(height, now) {
+            self.status = ProposalStatus::Expired;
+            log::info!("Proposal {} expired", self.reasoning_hash);
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use super::block::Block;
+use crate::{
+    types::{
+        blockchain::{ActionType, AgentRole, Transaction},
+        error::ErrorTypes,
+    },
+    utils::crypto::verify_admission,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+fn role_allows(role: &AgentRole, action_type: &ActionType) -> bool {
+    match action_type {
+        ActionType::ProposeUpdate => *role == AgentRole::Proposer,
+        ActionType::EvaluateUpdate | ActionType::VoteAccept | ActionType::VoteReject => {
+            *role != AgentRole::Proposer
+        }
+        ActionType::FinalizeBlock => *role == AgentRole::Validator,
+        ActionType::FlagMalicious | ActionType::RegisterAgent => true,
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AgentRecord {
+    pub agent_id: String,
+
+    pub public_key: String,
+
+    pub role: AgentRole,
+
+    // height of the block the registration was included in
+    pub joined_at: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AgentRegistry {
+    pub agents: HashMap<String, AgentRecord>,
+}
+
+impl AgentRegistry {
+    pub fn from_blocks<'a>(blocks: impl IntoIterator<Item = &'a Block>) -> AgentRegistry {
+        let mut registry = AgentRegistry::default();
+        for block in blocks {
+            registry.apply_block(block);
+        }
+
+        registry
+    }
+
+    pub fn apply_block(&mut self, block: &Block) {
+        for tx in block
+            .transactions
+            .iter()
+            .filter(|tx| tx.action_type == ActionType::RegisterAgent)
+        {
+            let Some(registration) = &tx.payload.agent_registration else {
+                continue;
+            };
+
+            if self.agents.contains_key(&tx.agent_id) {
+                log::warn!(
+                    "Ignoring duplicate registration of agent {} in block {}",
+                    tx.agent_id,
+                    block.index
+                );
+                continue;
+            }
+
+            self.agents.insert(
+                tx.agent_id.clone(),
+                AgentRecord {
+                    agent_id: tx.agent_id.clone(),
+                    public_key: tx.public_key.clone(),
+                    role: registration.role.clone(),
+                    joined_at: block.index,
+                },
+            );
+        }
+    }
+
+    pub fn get(&self, agent_id: &str) -> Option<&AgentRecord> {
+        self.agents.get(agent_id)
+    }
+
+    pub fn is_registered(&self, agent_id: &str) -> bool {
+        self.agents.contains_key(agent_id)
+    }
+
+    // Number of registered agents whose role lets them vote, proposals are decided by them
+    pub fn electorate(&self) -> usize {
+        self.agents
+            .values()
+            .filter(|record| role_allows(&record.role, &ActionType::VoteAccept))
+            .count()
+    }
+
+    // Registered validators in the order they take turns producing blocks
+    pub fn validators(&self) -> Vec<&AgentRecord> {
+        let mut validators: Vec<&AgentRecord> = self
+            .agents
+            .values()
+            .filter(|record| record.role == AgentRole::Validator)
+            .collect();
+        validators.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+        validators
+    }
+
+    // The validator whose turn it is to propose the block at `height` in `round`: round-robin
+    // with round 0 of block 1 going to the first one, every further round to the next one. `None`
+    // for genesis or while no validator is registered.
+    pub fn producer(&self, height: u32, round: u32) -> Option<&AgentRecord> {
+        let validators = self.validators();
+        if height == 0 || validators.is_empty() {
+            return None;
+        }
+
+        let turn = (height as u64 - 1 + round as u64) % validators.len() as u64;
+        Some(validators[turn as usize])
+    }
+
+    // Checks that the sender is a registered agent, signed with its registered key and that its
+    // role permits the action. The signature itself is checked separately.
+    pub fn authorize(&self, tx: &Transaction) -> Result<&AgentRecord, ErrorTypes> {
+        let record = self.get(&tx.agent_id).ok_or_else(|| {
+            ErrorTypes::UnknownAgent(format!("Agent {} is not registered", tx.agent_id))
+        })?;
+
+        if record.public_key != tx.public_key {
+            return Err(ErrorTypes::Unauthorized(format!(
+                "Transaction is not signed with the registered key of agent {}",
+                tx.agent_id
+            )));
+        }
+
+        if !role_allows(&record.role, &tx.action_type) {
+            return Err(ErrorTypes::Unauthorized(format!(
+                "Agent {} with role {:?} may not submit {:?}",
+                tx.agent_id, record.role, tx.action_type
+            )));
+        }
+
+        Ok(record)
+    }
+
+    // Checks a registration in the genesis block, where agents of every role register themselves
+    pub fn validate_genesis_registration(&self, tx: &Transaction) -> Result<(), ErrorTypes> {
+        if tx.agent_id.trim().is_empty() {
+            return Err(ErrorTypes::InvalidRegistration(
+                "Agent ID is empty".to_string(),
+            ));
+        }
+
+        if tx.payload.agent_registration.is_none() {
+            return Err(ErrorTypes::InvalidRegistration(format!(
+                "Registration of agent {} carries no role",
+                tx.agent_id
+            )));
+        }
+
+        if self.is_registered(&tx.agent_id) {
+            return Err(ErrorTypes::InvalidRegistration(format!(
+                "Agent {} is already registered",
+                tx.agent_id
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Checks a registration after genesis. Proposers may register themselves, evaluators and
+    // validators decide proposals and produce blocks, so a registered validator has to admit them.
+    pub fn validate_registration(&self, tx: &Transaction) -> Result<(), ErrorTypes> {
+        self.validate_genesis_registration(tx)?;
+
+        let Some(registration) = &tx.payload.agent_registration else {
+            return Ok(());
+        };
+        if registration.role == AgentRole::Proposer {
+            return Ok(());
+        }
+
+        let Some(admission) = &registration.admitted_by else {
+            return Err(ErrorTypes::Unauthorized(format!(
+                "Agent {} may not register itself as {:?}, a validator has to admit it",
+                tx.agent_id, registration.role
+            )));
+        };
+        let validator = self
+            .get(&admission.validator)
+            .filter(|record| record.role == AgentRole::Validator)
+            .ok_or_else(|| {
+                ErrorTypes::Unauthorized(format!(
+                    "Agent {} is admitted by {}, which is not a registered validator",
+                    tx.agent_id, admission.validator
+                ))
+            })?;
+
+        verify_admission(tx, &registration.role, admission, &validator.public_key)
+    }
+}
@@ -0,0 +1,343 @@
+use super::block::Block;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+// Segments are rolled over once they grow past this size, a single block may still exceed it
+const SEGMENT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+// Every record is `[payload length: u32 BE][sha256 of payload: 32 bytes][payload: block JSON]`
+const RECORD_HEADER_BYTES: u64 = 4 + 32;
+
+#[derive(Clone, Copy, Debug)]
+struct BlockLocation {
+    segment: u32,
+
+    offset: u64,
+
+    len: u32,
+}
+
+// Append-only, disk-backed store of blocks. Blocks live in numbered segment files under
+// `dir`, the height and hash indexes are kept in memory and rebuilt by scanning the
+// segments when the store is opened.
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+
+    active: File,
+
+    active_segment: u32,
+
+    active_len: u64,
+
+    by_height: Vec<BlockLocation>,
+
+    by_hash: HashMap<String, u32>,
+}
+
+fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("segment-{:06}.dat", segment))
+}
+
+fn list_segments(dir: &Path) -> io::Result<Vec<u32>> {
+    let mut segments: Vec<u32> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("segment-"))
+                .and_then(|name| name.strip_suffix(".dat"))
+                .and_then(|number| number.parse().ok())
+        })
+        .collect();
+    segments.sort();
+
+    Ok(segments)
+}
+
+// What `read_record` found at an offset
+enum Record {
+    // the payload and the offset the next record starts at
+    Intact(Vec<u8>, u64),
+
+    // the segment ends before the header or the payload does
+    Torn,
+
+    // complete, but the payload does not match its checksum, with the offset the record ends at
+    Corrupt(u64),
+}
+
+fn read_record(file: &mut File, offset: u64, file_len: u64) -> io::Result<Record> {
+    if file_len - offset < RECORD_HEADER_BYTES {
+        return Ok(Record::Torn);
+    }
+
+    let mut header = [0u8; RECORD_HEADER_BYTES as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut header)?;
+
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    if file_len - offset - RECORD_HEADER_BYTES < len {
+        return Ok(Record::Torn);
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    file.read_exact(&mut payload)?;
+
+    let end = offset + RECORD_HEADER_BYTES + len;
+    if Sha256::digest(&payload).as_slice() != &header[4..] {
+        return Ok(Record::Corrupt(end));
+    }
+
+    Ok(Record::Intact(payload, end))
+}
+
+// Makes the creation of a segment file durable, not only its contents
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// Where the last segment ends in a record a write never completed
+#[derive(Clone, Copy, Debug)]
+pub struct TornTail {
+    pub segment: u32,
+
+    pub offset: u64,
+
+    // height the torn record would have had, every block below it is intact
+    pub height: usize,
+}
+
+// Everything read from the segments, without changing them
+struct Scan {
+    blocks: Vec<Block>,
+
+    by_height: Vec<BlockLocation>,
+
+    by_hash: HashMap<String, u32>,
+
+    torn_tail: Option<TornTail>,
+}
+
+// Reads every segment in `dir`. Only a torn final record of the last segment is the remains of
+// an incomplete write: a short header or payload, or a checksum mismatch on the record that ends
+// the segment. Anything else that does not check out is corruption and fails the scan.
+fn scan(dir: &Path) -> io::Result<Scan> {
+    let segments = list_segments(dir)?;
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut by_height = Vec::new();
+    let mut by_hash = HashMap::new();
+    let mut torn_tail = None;
+
+    for (position, &segment) in segments.iter().enumerate() {
+        let is_last = position + 1 == segments.len();
+        let mut file = File::open(segment_path(dir, segment))?;
+        let file_len = file.metadata()?.len();
+        let mut offset = 0;
+
+        while offset < file_len {
+            let height = by_height.len();
+            let corrupt = |reason: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} in segment {} at offset {}, where block {} was expected",
+                        reason, segment, offset, height
+                    ),
+                )
+            };
+
+            let (payload, end) = match read_record(&mut file, offset, file_len)? {
+                Record::Intact(payload, end) => (payload, end),
+                Record::Corrupt(end) if is_last && end == file_len => {
+                    torn_tail = Some(TornTail {
+                        segment,
+                        offset,
+                        height,
+                    });
+                    break;
+                }
+                Record::Torn if is_last => {
+                    torn_tail = Some(TornTail {
+                        segment,
+                        offset,
+                        height,
+                    });
+                    break;
+                }
+                Record::Corrupt(_) => return Err(corrupt("Checksum mismatch".to_string())),
+                Record::Torn => return Err(corrupt("Incomplete block record".to_string())),
+            };
+
+            let block: Block = serde_json::from_slice(&payload)
+                .map_err(|e| corrupt(format!("Unreadable block record ({})", e)))?;
+            if block.index as usize != height {
+                return Err(corrupt(format!("Block {} out of order", block.index)));
+            }
+
+            if let Some(hash) = &block.hash {
+                by_hash.insert(hash.clone(), block.index);
+            }
+            by_height.push(BlockLocation {
+                segment,
+                offset,
+                len: (end - offset - RECORD_HEADER_BYTES) as u32,
+            });
+            blocks.push(block);
+            offset = end;
+        }
+    }
+
+    Ok(Scan {
+        blocks,
+        by_height,
+        by_hash,
+        torn_tail,
+    })
+}
+
+impl BlockStore {
+    // Reads the blocks stored in `dir` without changing anything, along with the torn record
+    // `open` would cut off, if there is one
+    pub fn scan(dir: impl AsRef<Path>) -> io::Result<(Vec<Block>, Option<TornTail>)> {
+        let scan = scan(dir.as_ref())?;
+        Ok((scan.blocks, scan.torn_tail))
+    }
+
+    // Opens the store in `dir`, creating it if needed, and returns every stored block in
+    // height order. A torn record at the end of the last segment is the remains of a write
+    // that never completed, it is cut off so the store ends at the last valid block.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<(BlockStore, Vec<Block>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let Scan {
+            blocks,
+            by_height,
+            by_hash,
+            torn_tail,
+        } = scan(&dir)?;
+
+        if let Some(TornTail {
+            segment, offset, ..
+        }) = torn_tail
+        {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(segment_path(&dir, segment))?;
+            log::warn!(
+                "Truncating segment {} from {} to {} bytes after an incomplete write",
+                segment,
+                file.metadata()?.len(),
+                offset
+            );
+            file.set_len(offset)?;
+            file.sync_all()?;
+        }
+
+        let segments = list_segments(&dir)?;
+        let active_segment = segments.last().copied().unwrap_or(0);
+        let active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, active_segment))?;
+        if segments.is_empty() {
+            sync_dir(&dir)?;
+        }
+        let active_len = active.metadata()?.len();
+
+        log::info!(
+            "Opened block store at {:?} with {} block(s)",
+            dir,
+            blocks.len()
+        );
+
+        Ok((
+            BlockStore {
+                dir,
+                active,
+                active_segment,
+                active_len,
+                by_height,
+                by_hash,
+            },
+            blocks,
+        ))
+    }
+
+    // Appends the block and fsyncs before returning, the block must be the next height
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        if block.index as usize != self.by_height.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Expected block at height {}, got {}",
+                    self.by_height.len(),
+                    block.index
+                ),
+            ));
+        }
+
+        let payload = serde_json::to_vec(block)?;
+
+        if self.active_len > 0 && self.active_len + payload.len() as u64 > SEGMENT_MAX_BYTES {
+            self.active_segment += 1;
+            self.active = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment_path(&self.dir, self.active_segment))?;
+            sync_dir(&self.dir)?;
+            self.active_len = 0;
+        }
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_BYTES as usize + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&Sha256::digest(&payload));
+        record.extend_from_slice(&payload);
+
+        self.active.write_all(&record)?;
+        self.active.sync_data()?;
+
+        if let Some(hash) = &block.hash {
+            self.by_hash.insert(hash.clone(), block.index);
+        }
+        self.by_height.push(BlockLocation {
+            segment: self.active_segment,
+            offset: self.active_len,
+            len: payload.len() as u32,
+        });
+        self.active_len += record.len() as u64;
+
+        Ok(())
+    }
+
+    pub fn height(&self) -> usize {
+        self.by_height.len()
+    }
+
+    pub fn get_by_height(&self, height: u32) -> io::Result<Option<Block>> {
+        let Some(location) = self.by_height.get(height as usize).copied() else {
+            return Ok(None);
+        };
+
+        let mut file = File::open(segment_path(&self.dir, location.segment))?;
+        let mut payload = vec![0u8; location.len as usize];
+        file.seek(SeekFrom::Start(location.offset + RECORD_HEADER_BYTES))?;
+        file.read_exact(&mut payload)?;
+
+        Ok(Some(serde_json::from_slice(&payload)?))
+    }
+
+    pub fn get_by_hash(&self, hash: &str) -> io::Result<Option<Block>> {
+        match self.by_hash.get(hash) {
+            Some(height) => self.get_by_height(*height),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use super::{
+    block::Block, commit::check_commit, index::ChainIndex, init::Blockchain,
+    registry::AgentRegistry,
+};
+use crate::{
+    types::{blockchain::ActionType, error::ErrorTypes},
+    utils::{
+        crypto::{verify_block_signature, verify_transaction},
+        hasher::merkle_root,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum VerificationFailure {
+    IndexGap,
+
+    BrokenLink,
+
+    HashMismatch,
+
+    MerkleRootMismatch,
+
+    TimestampNotIncreasing,
+
+    // the block is not from the validator whose turn it was, or no validator is registered
+    WrongProducer,
+
+    // the block is not signed with the registered key of its producer
+    InvalidBlockSignature,
+
+    // no commit certificate, or one without precommits from more than 2/3 of the validators
+    InvalidCommit,
+
+    InvalidSignature,
+
+    // the agent already used the nonce earlier in the chain or in the same block
+    ReplayedNonce,
+
+    // signed correctly but not allowed by the registry: unknown sender, wrong key or role,
+    // or a malformed or duplicate registration
+    InvalidTransaction,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VerificationError {
+    // index of the offending block
+    pub height: u32,
+
+    pub kind: VerificationFailure,
+
+    // position of the offending transaction in the block, if the failure is about one
+    pub tx_index: Option<usize>,
+
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VerificationReport {
+    // blocks that passed every check, the failing block is not counted
+    pub blocks_checked: usize,
+
+    pub height: u32,
+
+    // the first check that failed, `None` if the whole chain is consistent
+    pub failure: Option<VerificationError>,
+}
+
+impl VerificationReport {
+    pub fn is_valid(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+fn failure(
+    block: &Block,
+    kind: VerificationFailure,
+    tx_index: Option<usize>,
+    reason: String,
+) -> VerificationError {
+    VerificationError {
+        height: block.index,
+        kind,
+        tx_index,
+        reason,
+    }
+}
+
+// Checks `block` as the successor of `prev` (`None` for the genesis block), with `registry` and
+// `index` built from the blocks before it. Every block after genesis has to be committed.
+pub fn check_block(
+    prev: Option<&Block>,
+    block: &Block,
+    registry: &AgentRegistry,
+    index: &ChainIndex,
+) -> Result<(), VerificationError> {
+    check_contents(prev, block, registry, index)?;
+    if prev.is_none() {
+        return Ok(());
+    }
+
+    let Some(commit) = &block.commit else {
+        return Err(failure(
+            block,
+            VerificationFailure::InvalidCommit,
+            None,
+            format!("Block {} carries no commit certificate", block.index),
+        ));
+    };
+    check_commit(block, commit, registry)
+        .map_err(|reason| failure(block, VerificationFailure::InvalidCommit, None, reason))
+}
+
+// Everything `check_block` checks but the commit certificate, what a proposed block has to pass
+// before validators vote on it
+pub fn check_contents(
+    prev: Option<&Block>,
+    block: &Block,
+    registry: &AgentRegistry,
+    index: &ChainIndex,
+) -> Result<(), VerificationError> {
+    let expected_index = prev.map(|prev| prev.index + 1).unwrap_or(0);
+    if block.index != expected_index {
+        return Err(failure(
+            block,
+            VerificationFailure::IndexGap,
+            None,
+            format!(
+                "Expected block at height {}, got {}",
+                expected_index, block.index
+            ),
+        ));
+    }
+
+    let expected_prev_hash = match prev {
+        Some(prev) => prev.hash.clone().unwrap_or_default(),
+        None => "0".to_string(),
+    };
+    if block.prev_hash != expected_prev_hash {
+        return Err(failure(
+            block,
+            VerificationFailure::BrokenLink,
+            None,
+            format!(
+                "Block {} links to {} instead of {}",
+                block.index, block.prev_hash, expected_prev_hash
+            ),
+        ));
+    }
+
+    if block.hash.as_deref() != Some(block.compute_hash().as_str()) {
+        return Err(failure(
+            block,
+            VerificationFailure::HashMismatch,
+            None,
+            format!("Hash of block {} does not match its contents", block.index),
+        ));
+    }
+
+    if prev.is_some() {
+        let Some(expected) = registry.producer(block.index, block.round) else {
+            return Err(failure(
+                block,
+                VerificationFailure::WrongProducer,
+                None,
+                format!(
+                    "No validator is registered to produce block {}",
+                    block.index
+                ),
+            ));
+        };
+        if block.producer != expected.agent_id {
+            return Err(failure(
+                block,
+                VerificationFailure::WrongProducer,
+                None,
+                format!(
+                    "Block {} is produced by {}, in round {} it was the turn of {}",
+                    block.index, block.producer, block.round, expected.agent_id
+                ),
+            ));
+        }
+        verify_block_signature(block, &expected.public_key).map_err(|e| {
+            failure(
+                block,
+                VerificationFailure::InvalidBlockSignature,
+                None,
+                format!("{:?}", e),
+            )
+        })?;
+    }
+
+    if block.merkle_root.as_deref() != Some(merkle_root(&block.transactions).as_str()) {
+        return Err(failure(
+            block,
+            VerificationFailure::MerkleRootMismatch,
+            None,
+            format!(
+                "Merkle root of block {} does not match its transactions",
+                block.index
+            ),
+        ));
+    }
+
+    if let Some(prev) = prev
+        && block.timestamp <= prev.timestamp
+    {
+        return Err(failure(
+            block,
+            VerificationFailure::TimestampNotIncreasing,
+            None,
+            format!(
+                "Block {} is timestamped {}, not after block {} at {}",
+                block.index, block.timestamp, prev.index, prev.timestamp
+            ),
+        ));
+    }
+
+    let mut registered_here = HashSet::new();
+    // highest nonce of every agent so far, the chain's and then this block's
+    let mut last_nonces: HashMap<&str, u64> = HashMap::new();
+    for (tx_index, tx) in block.transactions.iter().enumerate() {
+        verify_transaction(tx).map_err(|e| {
+            failure(
+                block,
+                VerificationFailure::InvalidSignature,
+                Some(tx_index),
+                format!("{:?}", e),
+            )
+        })?;
+
+        let last = last_nonces
+            .get(tx.agent_id.as_str())
+            .copied()
+            .or_else(|| index.last_nonce(&tx.agent_id));
+        if let Some(last) = last
+            && tx.nonce <= last
+        {
+            return Err(failure(
+                block,
+                VerificationFailure::ReplayedNonce,
+                Some(tx_index),
+                format!(
+                    "Nonce {} of agent {} is not above its last nonce {}",
+                    tx.nonce, tx.agent_id, last
+                ),
+            ));
+        }
+        last_nonces.insert(&tx.agent_id, tx.nonce);
+
+        let allowed = if tx.action_type == ActionType::RegisterAgent {
+            let valid = if prev.is_none() {
+                registry.validate_genesis_registration(tx)
+            } else {
+                registry.validate_registration(tx)
+            };
+            valid.and_then(|()| {
+                if registered_here.insert(tx.agent_id.clone()) {
+                    Ok(())
+                } else {
+                    Err(ErrorTypes::InvalidRegistration(format!(
+                        "Agent {} is registered twice",
+                        tx.agent_id
+                    )))
+                }
+            })
+        } else {
+            registry.authorize(tx).map(|_| ())
+        };
+        allowed.map_err(|e| {
+            failure(
+                block,
+                VerificationFailure::InvalidTransaction,
+                Some(tx_index),
+                format!("{:?}", e),
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+impl Blockchain {
+    // Walks the whole chain from genesis and reports the first block that does not check out
+    pub fn verify(&self) -> VerificationReport {
+        let mut registry = AgentRegistry::default();
+        let mut index = ChainIndex::default();
+        let mut prev: Option<&Block> = None;
+        let mut blocks_checked = 0;
+
+        for block in self.blocks.iter() {
+            if let Err(failure) = check_block(prev, block, &registry, &index) {
+                return VerificationReport {
+                    blocks_checked,
+                    height: self.height(),
+                    failure: Some(failure),
+                };
+            }
+
+            registry.apply_block(block);
+            index.apply_block(block);
+            prev = Some(block);
+            blocks_checked += 1;
+        }
+
+        VerificationReport {
+            blocks_checked,
+            height: self.height(),
+            failure: None,
+        }
+    }
+}
@@ -0,0 +1,576 @@
+use crate::{
+    blockchain::{
+        block::Block,
+        init::{tally_votes, Blockchain, VoteTally},
+        mempool::Mempool,
+        proposal::ProposalState,
+        registry::AgentRecord,
+    },
+    net::chat::ConnectionPool,
+    node::SharedMempool,
+    p2p::P2PProtocol,
+    types::{
+        blockchain::{ActionType, Transaction, TransactionMessage},
+        error::{ErrorReport, ErrorTypes},
+    },
+    utils::{hasher::transaction_hash, message::MessageType},
+};
+use axum::{
+    body::Bytes,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+use tokio::sync::{mpsc, Mutex};
+
+// page size of `GET /blocks` when none is given, and the most it will return at once
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+// rejected submissions remembered for `GET /transactions/{id}/status`, oldest forgotten first
+const MAX_REMEMBERED_REJECTIONS: usize = 10_000;
+
+#[derive(Debug, Default)]
+pub struct Rejections {
+    errors: HashMap<String, ErrorReport>,
+
+    order: VecDeque<String>,
+}
+
+impl Rejections {
+    pub fn record(&mut self, id: &str, error: ErrorReport) {
+        if self.errors.insert(id.to_string(), error).is_none() {
+            self.order.push_back(id.to_string());
+        }
+        while self.order.len() > MAX_REMEMBERED_REJECTIONS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.errors.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ErrorReport> {
+        self.errors.get(id)
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: Arc<Mutex<ConnectionPool>>,
+    pub p2p: Arc<Mutex<P2PProtocol>>,
+
+    // read directly by the query endpoints so they do not wait on the P2P protocol
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: SharedMempool,
+    pub ws_peers: Arc<Mutex<Vec<mpsc::UnboundedSender<Message>>>>,
+    pub rejections: Arc<Mutex<Rejections>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    // height of the first block of the page
+    pub from: Option<u32>,
+
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlockPage {
+    pub blocks: Vec<Block>,
+
+    pub height: u32,
+
+    // `from` of the next page, `None` on the last page
+    pub next: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransactionRecord {
+    pub id: String,
+
+    pub transaction: Transaction,
+
+    // where the transaction was included, both `None` while it is still in the mempool
+    pub block_index: Option<u32>,
+
+    pub block_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TransactionStatus {
+    Pending,
+
+    Included {
+        block_index: u32,
+
+        block_hash: Option<String>,
+    },
+
+    Rejected {
+        code: String,
+
+        reason: String,
+    },
+}
+
+// Answer to a submission and to `GET /transactions/{id}/status`
+#[derive(Debug, Serialize)]
+pub struct TransactionReceipt {
+    pub id: String,
+
+    #[serde(flatten)]
+    pub status: TransactionStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Rejection {
+    // `None` if the transaction could not even be parsed
+    pub id: Option<String>,
+
+    #[serde(flatten)]
+    pub error: ErrorReport,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeStatus {
+    pub address: String,
+
+    pub height: u32,
+
+    pub last_block_hash: Option<String>,
+
+    pub peer_count: usize,
+
+    pub mempool_size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProposalView {
+    #[serde(flatten)]
+    pub proposal: ProposalState,
+
+    // votes and evaluations cover both the chain and the mempool
+    pub tally: VoteTally,
+
+    pub votes: Vec<Transaction>,
+
+    pub evaluations: Vec<Transaction>,
+
+    // hash of the block `finalized_in` points at
+    pub finalized_block_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentActivity {
+    pub agent_id: String,
+
+    // `None` if the agent is not registered (yet)
+    pub agent: Option<AgentRecord>,
+
+    // in chain order, pending transactions last
+    pub transactions: Vec<TransactionRecord>,
+}
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/transaction", post(submit_transaction))
+        .route("/ws", get(ws_handler))
+        .route("/status", get(get_status))
+        .route("/blocks", get(get_blocks))
+        .route("/blocks/{height}", get(get_block))
+        .route("/blocks/hash/{hash}", get(get_block_by_hash))
+        .route("/transactions/{id}", get(get_transaction))
+        .route("/transactions/{id}/status", get(get_transaction_status))
+        .route("/transactions/{id}/proof", get(get_transaction_proof))
+        .route("/mempool", get(get_mempool))
+        .route("/proposals", get(get_proposals))
+        .route("/proposals/{reasoning_hash}", get(get_proposal))
+        .route("/agents/{id}/activity", get(get_agent_activity))
+        .with_state(state)
+}
+
+fn not_found(reason: String) -> Response {
+    let error = ErrorReport {
+        code: "not_found".to_string(),
+        reason,
+    };
+    (StatusCode::NOT_FOUND, Json(error)).into_response()
+}
+
+// ---------------- HTTP ----------------
+
+async fn submit_transaction(State(state): State<AppState>, body: Bytes) -> Response {
+    let result = match parse_transaction(&body) {
+        Ok(tx_msg) => submit(&state, tx_msg).await,
+        Err(rejection) => Err(rejection),
+    };
+
+    match result {
+        Ok(receipt) => (StatusCode::ACCEPTED, Json(receipt)).into_response(),
+        Err(rejection) => (rejection_status(&rejection.error), Json(rejection)).into_response(),
+    }
+}
+
+fn parse_transaction(body: &[u8]) -> Result<TransactionMessage, Rejection> {
+    serde_json::from_slice(body).map_err(|e| Rejection {
+        id: None,
+        error: ErrorTypes::MalformedTransaction(format!("Error while parsing transaction: {}", e))
+            .report(),
+    })
+}
+
+fn rejection_status(error: &ErrorReport) -> StatusCode {
+    match error.code.as_str() {
+        "unknown_agent" | "unauthorized" => StatusCode::FORBIDDEN,
+        "duplicate_transaction" => StatusCode::CONFLICT,
+        "mempool_full" => StatusCode::SERVICE_UNAVAILABLE,
+        "storage_error" | "transaction_serialize_error" => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+// Validates the transaction synchronously and relays it to websocket clients and peers. The
+// outcome of a rejected transaction is kept so its status can still be looked up by id, unless
+// it was only turned down for being a duplicate.
+async fn submit(
+    state: &AppState,
+    tx_msg: TransactionMessage,
+) -> Result<TransactionReceipt, Rejection> {
+    let id = transaction_hash(&tx_msg.payload);
+    let msg_str = serde_json::to_string(&tx_msg).unwrap();
+
+    // ---- Core consensus logic ----
+    let p2p = state.p2p.lock().await;
+    if let Err(err) = p2p.handle_transaction(tx_msg.clone()).await {
+        let error = err.report();
+        // a duplicate says nothing about the transaction under that id, which went through
+        if !matches!(err, ErrorTypes::DuplicateTransaction(_)) {
+            state.rejections.lock().await.record(&id, error.clone());
+        }
+        return Err(Rejection {
+            id: Some(id),
+            error,
+        });
+    }
+
+    // ---- WebSocket broadcast ----
+    let peers = state.ws_peers.lock().await;
+    for peer in peers.iter() {
+        let _ = peer.send(Message::Text(msg_str.clone().into()));
+    }
+
+    // ---- P2P broadcast ----
+    // behind the proposal of any block the transaction just completed, which carries the vote
+    p2p.transport
+        .broadcast(p2p.envelope(MessageType::Transaction(tx_msg)));
+    drop(p2p);
+
+    // with a single validator a vote can commit a block right away, so the status is looked up
+    // rather than assumed
+    let status = transaction_status(state, &id)
+        .await
+        .unwrap_or(TransactionStatus::Pending);
+    Ok(TransactionReceipt { id, status })
+}
+
+async fn transaction_status(state: &AppState, id: &str) -> Option<TransactionStatus> {
+    if let Some((block, _)) = state.blockchain.lock().await.find_transaction(id) {
+        return Some(TransactionStatus::Included {
+            block_index: block.index,
+            block_hash: block.hash.clone(),
+        });
+    }
+
+    if state.mempool.lock().await.contains(id) {
+        return Some(TransactionStatus::Pending);
+    }
+
+    state
+        .rejections
+        .lock()
+        .await
+        .get(id)
+        .map(|error| TransactionStatus::Rejected {
+            code: error.code.clone(),
+            reason: error.reason.clone(),
+        })
+}
+
+async fn get_transaction_status(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match transaction_status(&state, &id).await {
+        Some(status) => Json(TransactionReceipt { id, status }).into_response(),
+        None => not_found(format!("Transaction {} is unknown", id)),
+    }
+}
+
+// Gathers the transactions of a proposal from the chain index and the mempool
+fn proposal_view(
+    blockchain: &Blockchain,
+    pending: &Mempool,
+    proposal: ProposalState,
+) -> ProposalView {
+    let transactions: Vec<Transaction> = blockchain
+        .index
+        .proposal(&proposal.reasoning_hash)
+        .iter()
+        .filter_map(|location| blockchain.transaction_at(*location))
+        .chain(pending.proposal(&proposal.reasoning_hash))
+        .cloned()
+        .collect();
+
+    let tally = tally_votes(&transactions)
+        .remove(&proposal.reasoning_hash)
+        .unwrap_or_default();
+    let (votes, evaluations) = transactions
+        .into_iter()
+        .filter(|tx| tx.action_type != ActionType::ProposeUpdate)
+        .partition(|tx| {
+            matches!(
+                tx.action_type,
+                ActionType::VoteAccept | ActionType::VoteReject
+            )
+        });
+    let finalized_block_hash = proposal
+        .finalized_in
+        .and_then(|height| blockchain.get_block(height))
+        .and_then(|block| block.hash.clone());
+
+    ProposalView {
+        proposal,
+        tally,
+        votes,
+        evaluations,
+        finalized_block_hash,
+    }
+}
+
+async fn get_proposals(State(state): State<AppState>) -> impl IntoResponse {
+    let proposals = state.p2p.lock().await.proposals().await;
+    let blockchain = state.blockchain.lock().await;
+    let pending = state.mempool.lock().await;
+
+    Json(
+        proposals
+            .into_iter()
+            .map(|proposal| proposal_view(&blockchain, &pending, proposal))
+            .collect::<Vec<_>>(),
+    )
+}
+
+async fn get_proposal(
+    State(state): State<AppState>,
+    Path(reasoning_hash): Path<String>,
+) -> Response {
+    let Some(proposal) = state
+        .p2p
+        .lock()
+        .await
+        .proposal_status(&reasoning_hash)
+        .await
+    else {
+        return not_found(format!("Proposal {} does not exist", reasoning_hash));
+    };
+    let blockchain = state.blockchain.lock().await;
+    let pending = state.mempool.lock().await;
+
+    Json(proposal_view(&blockchain, &pending, proposal)).into_response()
+}
+
+async fn get_agent_activity(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+) -> Response {
+    let blockchain = state.blockchain.lock().await;
+
+    let mut transactions: Vec<TransactionRecord> = blockchain
+        .index
+        .agent(&agent_id)
+        .iter()
+        .filter_map(|location| {
+            let block = blockchain.get_block(location.block_index)?;
+            let transaction = block.transactions.get(location.tx_index)?.clone();
+            Some(TransactionRecord {
+                id: transaction_hash(&transaction),
+                transaction,
+                block_index: Some(block.index),
+                block_hash: block.hash.clone(),
+            })
+        })
+        .collect();
+    transactions.extend(
+        state
+            .mempool
+            .lock()
+            .await
+            .iter()
+            .filter(|tx| tx.agent_id == agent_id)
+            .map(|tx| TransactionRecord {
+                id: transaction_hash(tx),
+                transaction: tx.clone(),
+                block_index: None,
+                block_hash: None,
+            }),
+    );
+
+    let agent = blockchain.registry.get(&agent_id).cloned();
+    if agent.is_none() && transactions.is_empty() {
+        return not_found(format!("Agent {} is unknown", agent_id));
+    }
+
+    Json(AgentActivity {
+        agent_id,
+        agent,
+        transactions,
+    })
+    .into_response()
+}
+
+async fn get_status(State(state): State<AppState>) -> impl IntoResponse {
+    let (height, last_block_hash) = {
+        let blockchain = state.blockchain.lock().await;
+        let last_block_hash = blockchain.get_last_block().and_then(|block| block.hash);
+        (blockchain.height(), last_block_hash)
+    };
+    let peer_count = state.pool.lock().await.clients.lock().await.len();
+    let address = state.p2p.lock().await.address.clone();
+
+    Json(NodeStatus {
+        address,
+        height,
+        last_block_hash,
+        peer_count,
+        mempool_size: state.mempool.lock().await.len(),
+    })
+}
+
+async fn get_blocks(
+    State(state): State<AppState>,
+    Query(page): Query<PageQuery>,
+) -> impl IntoResponse {
+    let from = page.from.unwrap_or(0);
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let blockchain = state.blockchain.lock().await;
+    let height = blockchain.height();
+    let blocks = blockchain.blocks_in_range(from, from.saturating_add(limit - 1));
+    let next = blocks
+        .last()
+        .filter(|block| block.index < height)
+        .map(|block| block.index + 1);
+
+    Json(BlockPage {
+        blocks,
+        height,
+        next,
+    })
+}
+
+async fn get_block(State(state): State<AppState>, Path(height): Path<u32>) -> Response {
+    match state.blockchain.lock().await.get_block(height) {
+        Some(block) => Json(block).into_response(),
+        None => not_found(format!("No block at height {}", height)),
+    }
+}
+
+async fn get_block_by_hash(State(state): State<AppState>, Path(hash): Path<String>) -> Response {
+    match state.blockchain.lock().await.get_block_by_hash(&hash) {
+        Some(block) => Json(block).into_response(),
+        None => not_found(format!("No block with hash {}", hash)),
+    }
+}
+
+// Looks the transaction up in the chain first, then in the mempool
+async fn get_transaction(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    if let Some((block, tx_index)) = state.blockchain.lock().await.find_transaction(&id) {
+        return Json(TransactionRecord {
+            id,
+            transaction: block.transactions[tx_index].clone(),
+            block_index: Some(block.index),
+            block_hash: block.hash.clone(),
+        })
+        .into_response();
+    }
+
+    let pending = state.mempool.lock().await.get(&id).cloned();
+    match pending {
+        Some(transaction) => Json(TransactionRecord {
+            id,
+            transaction,
+            block_index: None,
+            block_hash: None,
+        })
+        .into_response(),
+        None => not_found(format!("Transaction {} is unknown", id)),
+    }
+}
+
+async fn get_mempool(State(state): State<AppState>) -> impl IntoResponse {
+    Json(
+        state
+            .mempool
+            .lock()
+            .await
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>(),
+    )
+}
+
+async fn get_transaction_proof(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.blockchain.lock().await.transaction_proof(&id) {
+        Some(proof) => Json(proof).into_response(),
+        None => not_found(format!("Transaction {} is not in any block", id)),
+    }
+}
+
+// ---------------- WebSocket ----------------
+
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_ws(socket, state))
+}
+
+async fn handle_ws(socket: WebSocket, state: AppState) {
+    let (mut sender, mut receiver) = socket.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+
+    state.ws_peers.lock().await.push(tx.clone());
+
+    let send_task = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let _ = sender.send(msg).await;
+        }
+    });
+
+    while let Some(Ok(msg)) = receiver.next().await {
+        let Message::Text(text) = msg else {
+            continue;
+        };
+
+        let result = match parse_transaction(text.as_bytes()) {
+            Ok(tx_msg) => submit(&state, tx_msg).await,
+            Err(rejection) => Err(rejection),
+        };
+        if let Err(rejection) = result {
+            let _ = tx.send(Message::Text(
+                serde_json::to_string(&rejection).unwrap().into(),
+            ));
+        }
+    }
+
+    send_task.abort();
+}
@@ -1,7 +1,8 @@
 pub mod blockchain;
 pub mod http_server;
 pub mod net;
+pub mod node;
 pub mod p2p;
-pub mod server;
+pub mod sim;
 pub mod types;
 pub mod utils;
@@ -1,157 +1,163 @@
-use axum::{
-    extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
-    },
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
-};
 use clap::Parser;
-use futures::{SinkExt, StreamExt};
 use log4rs::config::Deserializers;
 use no_cap::{
-    blockchain::init::Blockchain,
-    net::chat::{handle_connection, ConnectionPool},
-    p2p::P2PProtocol,
-    server::handler::Server as HandlerServer,
-    types::{args::Args, blockchain::TransactionMessage},
-    utils::reqwest::get_external_ip,
-};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{
-    io::AsyncWriteExt,
-    net::TcpListener,
-    sync::{mpsc, Mutex},
+    blockchain::{init::Blockchain, mempool::Mempool, store::BlockStore},
+    net::discovery::{advertised_address, run_responder},
+    node::Node,
+    p2p::bft::Bft,
+    types::{
+        args::{Args, Command},
+        blockchain::TransactionMessage,
+        config::Config,
+    },
+    utils::crypto::{generate_keypair, load_keypair, sign_transaction},
 };
-use tower_http::cors::{Any, CorsLayer};
-
-#[derive(Clone)]
-struct AppState {
-    pool: Arc<Mutex<ConnectionPool>>,
-    p2p: Arc<Mutex<P2PProtocol>>,
-    ws_peers: Arc<Mutex<Vec<mpsc::UnboundedSender<Message>>>>,
-}
+use tokio::net::TcpListener;
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    log4rs::init_file(&args.log_config, Deserializers::new()).unwrap();
-    dotenv::from_path(&args.dotenv).ok();
-    get_external_ip().await.unwrap();
-
-    // ---- Core shared state ----
-    let blockchain = Arc::new(Mutex::new(Blockchain::init()));
-    let pool = Arc::new(Mutex::new(ConnectionPool::init()));
-    let ws_peers = Arc::new(Mutex::new(Vec::new()));
+    sodiumoxide::init().expect("Failed to initialize libsodium");
 
-    let server = Arc::new(Mutex::new(HandlerServer {
-        blockchain,
-        connection_pool: pool.clone(),
-        p2p_protocol: None,
-    }));
+    dotenv::from_path(&args.dotenv).ok();
 
-    let p2p = Arc::new(Mutex::new(P2PProtocol::new(server.clone()).await));
-    server.lock().await.p2p_protocol = Some(p2p.clone());
+    if let Some(command) = args.command.clone() {
+        std::process::exit(run_command(command, &args));
+    }
 
-    let app_state = AppState {
-        pool: pool.clone(),
-        p2p,
-        ws_peers: ws_peers.clone(),
+    let config = match Config::resolve(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration:\n{}", e.reason());
+            std::process::exit(1);
+        }
     };
-
-    // ---- CORS ----
-    let cors = CorsLayer::new()
-        .allow_origin(Any) // during dev
-        .allow_methods(Any)
-        .allow_headers(Any);
-
-    // ---- Axum Router ----
-    let app = Router::new()
-        .route("/transaction", post(submit_transaction))
-        .route("/ws", get(ws_handler))
-        .layer(cors)
-        .with_state(app_state);
+    log4rs::init_file(&config.node.log_config, Deserializers::new()).unwrap();
+    log::info!("Starting with {:?}", config);
+    let key = config.node_key().unwrap();
+    if let Some(key) = &key {
+        log::info!("Node key: {}", key.public_key);
+    }
+    let address = advertised_address(&config).await;
+    log::info!("Advertising P2P address {}", address);
+
+    let blockchain = Blockchain::open(
+        &config.node.data_dir,
+        Blockchain::load_genesis_transactions(&config.node.genesis),
+        config.consensus,
+    )
+    .unwrap();
+    let node = Node::new(
+        blockchain,
+        Mempool::new(config.mempool),
+        address,
+        Bft::new(key, config.bft),
+    )
+    .await;
+    node.spawn_maintenance();
+
+    // ---- Peers we know of ----
+    for peer in config.p2p.peers.clone() {
+        node.spawn_peer(peer);
+    }
 
     // ---- HTTP + WS Server ----
-    let http_addr: SocketAddr = "0.0.0.0:3000".parse().unwrap();
-    tokio::spawn(async move {
-        axum::serve(TcpListener::bind(http_addr).await.unwrap(), app)
-            .await
-            .unwrap();
-    });
-
-    // ---- TCP P2P Server ----
-    let tcp_listener = TcpListener::bind("0.0.0.0:2373").await.unwrap();
-    log::info!("P2P TCP listening on 2373");
-
-    loop {
-        let (stream, addr) = tcp_listener.accept().await.unwrap();
-        log::info!("P2P connection from {}", addr);
-
-        let pool_clone = pool.clone();
-        let server_clone = server.clone();
+    let http_listener = TcpListener::bind(config.http.listen).await.unwrap();
+    tokio::spawn(node.clone().serve_http(http_listener));
 
+    // ---- Address responder ----
+    if config.discovery.respond {
+        let listen = config.p2p.listen;
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(pool_clone, stream, server_clone).await {
-                log::error!("TCP error: {:?}", e);
+            if let Err(e) = run_responder(listen).await {
+                log::error!("Address responder stopped: {:?}", e);
             }
         });
     }
-}
-
-// ---------------- HTTP ----------------
-
-async fn submit_transaction(
-    State(state): State<AppState>,
-    Json(tx_msg): Json<TransactionMessage>,
-) -> impl IntoResponse {
-    let msg_str = serde_json::to_string(&tx_msg).unwrap();
-
-    // ---- Core consensus logic ----
-    state
-        .p2p
-        .lock()
-        .await
-        .handle_transaction(&msg_str, None, Some(state.ws_peers.clone()))
-        .await;
-
-    // ---- WebSocket broadcast ----
-    let peers = state.ws_peers.lock().await;
-    for peer in peers.iter() {
-        let _ = peer.send(Message::Text(msg_str.clone().into()));
-    }
-
-    // ---- TCP broadcast ----
-    let pool = state.pool.lock().await;
-    let clients = pool.clients.lock().await;
-    for client in clients.iter() {
-        let mut writer = client.writer.lock().await;
-        let _ = writer.write_all(msg_str.as_bytes()).await;
-    }
-
-    (axum::http::StatusCode::OK, "Transaction accepted")
-}
-
-// ---------------- WebSocket ----------------
 
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_ws(socket, state))
+    // ---- TCP P2P Server ----
+    let p2p_listener = TcpListener::bind(config.p2p.listen).await.unwrap();
+    node.serve_p2p(p2p_listener).await.unwrap();
 }
 
-async fn handle_ws(socket: WebSocket, state: AppState) {
-    let (mut sender, mut receiver) = socket.split();
-    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+// ---------------- CLI ----------------
 
-    state.ws_peers.lock().await.push(tx);
+// Runs a one-off subcommand and returns the process exit code
+fn run_command(command: Command, args: &Args) -> i32 {
+    match command {
+        Command::Keygen => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&generate_keypair()).unwrap()
+            );
+        }
+        Command::Sign { key, input, nonce } => {
+            let keypair = match load_keypair(&key) {
+                Ok(keypair) => keypair,
+                Err(e) => {
+                    eprintln!("{}", e.reason());
+                    return 1;
+                }
+            };
+            let mut tx_msg: TransactionMessage =
+                serde_json::from_str(&std::fs::read_to_string(input).unwrap()).unwrap();
+
+            if let Some(nonce) = nonce {
+                tx_msg.payload.nonce = nonce;
+            }
 
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            let _ = sender.send(msg).await;
+            match sign_transaction(&mut tx_msg.payload, &keypair) {
+                Ok(()) => println!("{}", serde_json::to_string(&tx_msg).unwrap()),
+                Err(e) => {
+                    eprintln!("Failed to sign transaction: {:?}", e);
+                    return 1;
+                }
+            }
         }
-    });
+        Command::Verify { repair } => {
+            let data_dir = match Config::resolve(args) {
+                Ok(config) => config.node.data_dir,
+                Err(e) => {
+                    eprintln!("Invalid configuration:\n{}", e.reason());
+                    return 1;
+                }
+            };
+            if !std::path::Path::new(&data_dir).is_dir() {
+                eprintln!("No chain stored in {}", data_dir);
+                return 1;
+            }
+            let scanned = if repair {
+                BlockStore::open(&data_dir).map(|(_, blocks)| (blocks, None))
+            } else {
+                BlockStore::scan(&data_dir)
+            };
+            let (blocks, torn_tail) = match scanned {
+                Ok(scanned) => scanned,
+                Err(e) => {
+                    eprintln!("Block store in {} is corrupt: {}", data_dir, e);
+                    return 1;
+                }
+            };
+            if let Some(torn) = torn_tail {
+                eprintln!(
+                    "Block store in {} ends in an incomplete record in segment {} at offset {}, \
+                     where block {} would be. Run `verify --repair` to cut it off.",
+                    data_dir, torn.segment, torn.offset, torn.height
+                );
+                return 1;
+            }
+            if blocks.is_empty() {
+                eprintln!("No chain stored in {}", data_dir);
+                return 1;
+            }
 
-    while let Some(Ok(_)) = receiver.next().await {}
+            let report = Blockchain::from_blocks(blocks).verify();
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if !report.is_valid() {
+                return 1;
+            }
+        }
+    }
 
-    send_task.abort();
+    0
 }
@@ -1,32 +1,36 @@
+use super::codec::{read_frame, write_message};
 use crate::{
-    server::handler::Server,
+    p2p::P2PProtocol,
     types::{
-        blockchain::{PeerAddr, TransactionMessage},
-        chat::{ChatError, ChatResponse},
+        blockchain::{Handshake, PeerAddr},
+        error::ErrorTypes,
     },
+    utils::message::{Envelope, MessageType, Meta},
 };
-use serde::{Deserialize, Serialize};
-use std::{
-    error::Error,
-    sync::Arc,
-    time::{Duration, SystemTime},
-};
+use std::{error::Error, io, sync::Arc};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
-    sync::{broadcast, Mutex},
+    io::BufReader,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::Mutex,
 };
 
 #[derive(Clone, Debug)]
 pub struct Client {
     pub nickname: String,
+    pub address: String,
     pub writer: Arc<Mutex<OwnedWriteHalf>>,
 }
 
 impl Client {
-    pub fn new(nickname: String, writer: Arc<Mutex<OwnedWriteHalf>>) -> Client {
-        let last_seen = SystemTime::now();
-        Self { nickname, writer }
+    pub fn new(nickname: String, address: String, writer: Arc<Mutex<OwnedWriteHalf>>) -> Client {
+        Self {
+            nickname,
+            address,
+            writer,
+        }
     }
 }
 
@@ -47,13 +51,16 @@ impl ConnectionPool {
         true
     }
 
-    pub async fn add_peer(&self, nickname: String, writer: Arc<Mutex<OwnedWriteHalf>>) -> Client {
-        let peer = Client::new(nickname, writer);
+    pub async fn add_peer(
+        &self,
+        nickname: String,
+        address: String,
+        writer: Arc<Mutex<OwnedWriteHalf>>,
+    ) -> Client {
+        let peer = Client::new(nickname, address, writer);
 
         self.clients.lock().await.push(peer.clone());
-
-        self.send_welcome_message(&peer).await.unwrap();
-        self.broadcast_join(&peer).await;
+        log::info!("{} ({}) has just joined!", peer.nickname, peer.address);
 
         peer
     }
@@ -74,37 +81,17 @@ impl ConnectionPool {
         self.clone()
     }
 
-    pub async fn send_welcome_message(&self, client: &Client) -> Result<ChatResponse, ChatError> {
-        let count = {
-            let client = self.clients.lock().await;
-            println!("Clients: {:?}", client);
-            client.len() - 1
-        };
-
-        let mut writer = client.writer.lock().await;
-        let message = format!("===\nWelcome {}!\n\nThere are {} user(s) besides you\n\nHelp:\nType anything to chat\n- /list will list all the connected users\n- /quit will disconnect you\n===", client.nickname, count);
-
-        match writer.write_all(message.as_bytes()).await {
-            Ok(_) => Ok(ChatResponse::WelcomeMessage(format!(
-                "Successfully sent welcome message to {:?}",
-                client.nickname
-            ))),
-            Err(e) => Err(ChatError::WelcomeMessage(format!(
-                "Error while sending welcome message to {:?}: {:?}",
-                client.nickname, e
-            ))),
-        }
-    }
-
-    pub async fn broadcast(&self, writer: Arc<Mutex<OwnedWriteHalf>>, message: String) {
-        let clients = self.clients.lock().await;
+    // Sends the message to every peer except the one behind `writer`. The peers are copied out
+    // first, so a slow one does not keep others from joining or leaving the pool.
+    pub async fn broadcast(&self, writer: Option<&Arc<Mutex<OwnedWriteHalf>>>, message: &Envelope) {
+        let clients = self.clients.lock().await.clone();
         for client in clients.iter() {
-            if Arc::ptr_eq(&client.writer, &writer) {
+            if writer.is_some_and(|writer| Arc::ptr_eq(&client.writer, writer)) {
                 continue;
             }
 
             let mut writer = client.writer.lock().await;
-            match writer.write_all(message.as_bytes()).await {
+            match write_message(&mut *writer, message).await {
                 Ok(_) => {
                     log::info!("Broadcast successfully done!\n");
                 }
@@ -115,46 +102,19 @@ impl ConnectionPool {
         }
     }
 
-    pub async fn broadcast_join(&self, client: &Client) {
-        self.broadcast(
-            client.clone().writer,
-            format!("{} has just joined!\n", client.nickname),
-        )
-        .await;
-    }
-
-    pub async fn broadcast_quit(&self, client: &Client) {
-        self.broadcast(
-            client.clone().writer,
-            format!("{} has just quit!\n", client.nickname),
-        )
-        .await;
-    }
-
-    pub async fn broadcast_new_message(&self, client: &Client, message: String) {
-        self.broadcast(
-            client.clone().writer,
-            format!("\n[{}]: {}\n", client.nickname, message),
-        )
-        .await;
-    }
-
-    pub async fn list_clients(&self, writer: Arc<Mutex<OwnedWriteHalf>>) {
-        let mut message = String::from("===\nCurrently connected users:");
-        let clients = self.clients.lock().await;
-
-        for user in clients.iter() {
-            if Arc::ptr_eq(&user.writer, &writer) {
-                message.push_str(&format!("\n - {} (you)", user.nickname));
-            } else {
-                message.push_str(&format!("\n - {}", user.nickname));
-            }
-        }
-        message.push_str("\n===");
-        let mut writer = writer.lock().await;
-
-        let _ = writer.write_all(message.as_bytes()).await;
-        let _ = writer.write_all(b"\n").await;
+    pub async fn peer_addresses(&self) -> Vec<PeerAddr> {
+        self.clients
+            .lock()
+            .await
+            .iter()
+            .filter_map(|client| {
+                let (ip, port) = client.address.rsplit_once(':')?;
+                Some(PeerAddr {
+                    ip: ip.to_string(),
+                    port: port.parse().ok()?,
+                })
+            })
+            .collect()
     }
 }
 //     pub async fn get_most_alive_clients(&self, count: usize) -> Vec<Client> {
@@ -180,72 +140,162 @@ impl ConnectionPool {
 //     }
 // }
 
-pub async fn handle_connection(
-    pool: Arc<Mutex<ConnectionPool>>,
-    stream: TcpStream,
-    server: Arc<Mutex<Server>>,
-) -> Result<(), Box<dyn Error>> {
-    let (reader, writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
-    let writer = Arc::new(Mutex::new(writer));
+// The error reply to a rejected message. Built while the protocol is locked, sent after the lock
+// is released.
+fn error_reply(p2p: &P2PProtocol, err: ErrorTypes) -> Envelope {
+    log::warn!("Rejected message from peer: {:?}", err);
+    p2p.envelope(MessageType::Error(err))
+}
 
-    // Ask for nickname
-    writer
-        .lock()
-        .await
-        .write_all(b"Choose your nickname: ")
-        .await?;
+// Writes `messages` to the peer in order. Never called with the protocol locked, a peer that
+// stops reading only holds up its own connection.
+async fn send_all(writer: &Arc<Mutex<OwnedWriteHalf>>, messages: &[Envelope]) {
+    let mut writer = writer.lock().await;
+    for message in messages {
+        P2PProtocol::send_message(&mut writer, message).await;
+    }
+}
+
+// Reads the next frame off the connection. A frame over the size limit cannot be skipped
+// safely, the peer is told why and the connection is treated as closed.
+async fn next_frame(
+    p2p: &Arc<Mutex<P2PProtocol>>,
+    writer: &Arc<Mutex<OwnedWriteHalf>>,
+    reader: &mut BufReader<OwnedReadHalf>,
+) -> io::Result<Option<Vec<u8>>> {
+    match read_frame(reader).await {
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            let err = ErrorTypes::FrameTooLarge(e.to_string());
+            let reply = error_reply(&*p2p.lock().await, err);
+            send_all(writer, &[reply]).await;
+            Ok(None)
+        }
+        result => result,
+    }
+}
 
-    let mut nickname_line = String::new();
-    reader.read_line(&mut nickname_line).await?;
-    let nickname = nickname_line.trim().to_string();
+// Reads the peer's handshake, anything else ends the connection
+async fn receive_handshake(
+    p2p_arc: &Arc<Mutex<P2PProtocol>>,
+    writer: &Arc<Mutex<OwnedWriteHalf>>,
+    reader: &mut BufReader<OwnedReadHalf>,
+) -> io::Result<Option<(Meta, Handshake)>> {
+    let Some(frame) = next_frame(p2p_arc, writer, reader).await? else {
+        return Ok(None);
+    };
+
+    let err = match Envelope::parse(&frame) {
+        Ok(Envelope {
+            meta,
+            message: MessageType::Handshake(handshake),
+        }) => return Ok(Some((meta, handshake))),
+        Ok(_) => ErrorTypes::UnsupportedMessage("Expected a handshake".to_string()),
+        Err(err) => err,
+    };
+    let reply = error_reply(&*p2p_arc.lock().await, err);
+    send_all(writer, &[reply]).await;
+    Ok(None)
+}
+
+async fn send_handshake(p2p_arc: &Arc<Mutex<P2PProtocol>>, writer: &Arc<Mutex<OwnedWriteHalf>>) {
+    let handshake = {
+        let p2p = p2p_arc.lock().await;
+        let block_height = p2p.blockchain.lock().await.height();
+        p2p.envelope(MessageType::Handshake(Handshake {
+            nickname: p2p.address.clone(),
+            block_height,
+        }))
+    };
+    send_all(writer, &[handshake]).await;
+}
 
+// Runs a connection once both sides have introduced themselves: catches up with the peer if it
+// is ahead, then handles its messages until it disconnects
+async fn run_session(
+    pool: Arc<Mutex<ConnectionPool>>,
+    p2p_arc: Arc<Mutex<P2PProtocol>>,
+    mut reader: BufReader<OwnedReadHalf>,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    meta: Meta,
+    handshake: Handshake,
+) -> Result<(), Box<dyn Error>> {
     let client = pool
         .lock()
         .await
-        .add_peer(nickname.clone(), writer.clone())
+        .add_peer(handshake.nickname.clone(), meta.address, writer.clone())
         .await;
+    log::info!(
+        "Handshake with {} done, peer is at height {}",
+        client.nickname,
+        handshake.block_height
+    );
+
+    let request = {
+        let p2p = p2p_arc.lock().await;
+        let request = p2p.sync_request(handshake.block_height).await;
+        request.map(|request| p2p.envelope(request))
+    };
+    if let Some(request) = request {
+        send_all(&writer, &[request]).await;
+    }
 
-    log::info!("Your nickname is: {:?}", nickname);
+    while let Some(frame) = next_frame(&p2p_arc, &writer, &mut reader).await? {
+        let replies: Vec<Envelope> = {
+            let p2p = p2p_arc.lock().await;
+            match Envelope::parse(&frame) {
+                Ok(message) => p2p
+                    .respond(message)
+                    .await
+                    .into_iter()
+                    .map(|reply| p2p.envelope(reply))
+                    .collect(),
+                Err(err) => vec![error_reply(&p2p, err)],
+            }
+        };
+        send_all(&writer, &replies).await;
+    }
 
-    let mut lines = reader.lines();
+    log::info!("{} has just quit!", client.nickname);
+    pool.lock().await.remove_peer(writer).await;
 
-    while let Some(line) = lines.next_line().await? {
-        let msg = line.trim();
-        if msg.is_empty() {
-            continue;
-        }
+    Ok(())
+}
 
-        println!("Message: {:?}", msg);
+pub async fn handle_connection(
+    pool: Arc<Mutex<ConnectionPool>>,
+    stream: TcpStream,
+    p2p_arc: Arc<Mutex<P2PProtocol>>,
+) -> Result<(), Box<dyn Error>> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let writer = Arc::new(Mutex::new(writer));
 
-        match msg {
-            "/quit" => {
-                pool.lock().await.broadcast_quit(&client).await;
-                log::error!("Client is disconnected!");
-                break;
-            }
-            "/list" => {
-                pool.lock().await.list_clients(writer.clone()).await;
-            }
-            _ => {
-                // P2P handling
-                let p2p_arc = {
-                    let server_guard = server.lock().await;
-                    server_guard.p2p_protocol.as_ref().unwrap().clone()
-                };
-                let p2p = p2p_arc.lock().await;
-                pool.lock()
-                    .await
-                    .broadcast_new_message(&client, msg.to_string())
-                    .await;
+    // The connecting side introduces itself first
+    let Some((meta, handshake)) = receive_handshake(&p2p_arc, &writer, &mut reader).await? else {
+        return Ok(());
+    };
+    send_handshake(&p2p_arc, &writer).await;
 
-                p2p.handle_transaction(msg, Some(writer.clone()), None)
-                    .await;
+    run_session(pool, p2p_arc, reader, writer, meta, handshake).await
+}
 
-                // Broadcast to all clients
-            }
-        }
-    }
+// Dials a known peer and runs the connection until it is closed
+pub async fn connect_to_peer(
+    pool: Arc<Mutex<ConnectionPool>>,
+    address: &str,
+    p2p_arc: Arc<Mutex<P2PProtocol>>,
+) -> Result<(), Box<dyn Error>> {
+    let stream = TcpStream::connect(address).await?;
+    log::info!("Connected to peer {}", address);
 
-    Ok(())
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let writer = Arc::new(Mutex::new(writer));
+
+    send_handshake(&p2p_arc, &writer).await;
+    let Some((meta, handshake)) = receive_handshake(&p2p_arc, &writer, &mut reader).await? else {
+        return Ok(());
+    };
+
+    run_session(pool, p2p_arc, reader, writer, meta, handshake).await
 }
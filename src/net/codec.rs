@@ -0,0 +1,62 @@
+use crate::utils::message::Envelope;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Upper bound on a single message, a peer announcing a bigger frame is cut off instead of
+// making us allocate whatever it asks for
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+// Every frame on the P2P port is `[payload length: u32 BE][payload]`
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                payload.len(),
+                MAX_FRAME_SIZE
+            ),
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+// Reads the next frame, `None` when the peer closed the connection between frames. An
+// oversized length prefix is reported as `InvalidData`.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                length, MAX_FRAME_SIZE
+            ),
+        ));
+    }
+
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Some(payload))
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Envelope,
+) -> io::Result<()> {
+    write_frame(writer, &message.to_bytes()).await
+}
@@ -0,0 +1,137 @@
+use crate::{
+    types::{
+        config::{Config, DiscoveryBackend, DiscoveryConfig},
+        error::ErrorTypes,
+    },
+    utils::reqwest::get_external_ip,
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+// A STUN-style exchange over UDP: the client sends this, the responder answers with the
+// "ip:port" it saw the datagram come from
+const WHOAMI_REQUEST: &[u8] = b"NO_CAP WHOAMI";
+
+// The address other nodes should dial to reach this node's P2P port, also what goes into the
+// `Meta` of every message we send. Never fails: without a configured or discovered address the
+// node falls back to a local one, so it still starts offline.
+pub async fn advertised_address(config: &Config) -> String {
+    if let Some(address) = &config.p2p.advertise {
+        return address.clone();
+    }
+
+    let ip = match discover_ip(&config.discovery).await {
+        Ok(ip) => {
+            log::info!("Discovered address {}", ip);
+            ip
+        }
+        Err(e) => {
+            let ip = fallback_ip(config.p2p.listen.ip());
+            log::warn!(
+                "{}, advertising {} instead, peers outside this network may not reach us",
+                e.reason(),
+                ip
+            );
+            ip
+        }
+    };
+
+    SocketAddr::new(ip, config.p2p.listen.port()).to_string()
+}
+
+pub async fn discover_ip(config: &DiscoveryConfig) -> Result<IpAddr, ErrorTypes> {
+    let timeout = Duration::from_secs(config.timeout_secs);
+
+    match config.backend {
+        DiscoveryBackend::None => Err(ErrorTypes::DiscoveryError(
+            "Address discovery is disabled".to_string(),
+        )),
+        DiscoveryBackend::Http => {
+            let ip = get_external_ip(&config.url, timeout).await.map_err(|e| {
+                ErrorTypes::DiscoveryError(format!("Error while asking {}: {}", config.url, e))
+            })?;
+            ip.parse().map_err(|_| {
+                ErrorTypes::DiscoveryError(format!("{} answered with {:?}", config.url, ip))
+            })
+        }
+        DiscoveryBackend::Stun => {
+            // validated at startup
+            let responder = config.responder.as_deref().unwrap_or_default();
+            tokio::time::timeout(timeout, ask_responder(responder))
+                .await
+                .map_err(|_| {
+                    ErrorTypes::DiscoveryError(format!("No answer from responder {}", responder))
+                })?
+        }
+        DiscoveryBackend::Interfaces => interface_ip().ok_or_else(|| {
+            ErrorTypes::DiscoveryError("No network interface with a usable address".to_string())
+        }),
+    }
+}
+
+async fn ask_responder(responder: &str) -> Result<IpAddr, ErrorTypes> {
+    let error = |e: std::io::Error| {
+        ErrorTypes::DiscoveryError(format!("Error while asking responder {}: {}", responder, e))
+    };
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(error)?;
+    socket.connect(responder).await.map_err(error)?;
+    socket.send(WHOAMI_REQUEST).await.map_err(error)?;
+
+    let mut buf = [0u8; 128];
+    let len = socket.recv(&mut buf).await.map_err(error)?;
+    std::str::from_utf8(&buf[..len])
+        .ok()
+        .and_then(|answer| answer.parse::<SocketAddr>().ok())
+        .map(|observed| observed.ip())
+        .ok_or_else(|| {
+            ErrorTypes::DiscoveryError(format!("Responder {} sent an invalid answer", responder))
+        })
+}
+
+// Answers `WHOAMI_REQUEST`s on the UDP side of the P2P port, so peers can use this node with
+// the `stun` backend
+pub async fn run_responder(listen: SocketAddr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(listen).await?;
+    log::info!("Address responder listening on udp/{}", listen);
+
+    let mut buf = [0u8; 128];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if &buf[..len] != WHOAMI_REQUEST {
+            continue;
+        }
+        if let Err(e) = socket.send_to(from.to_string().as_bytes(), from).await {
+            log::warn!("Error while answering {}: {}", from, e);
+        }
+    }
+}
+
+// First address of a non-loopback interface, IPv4 preferred
+fn interface_ip() -> Option<IpAddr> {
+    let addresses: Vec<IpAddr> = if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .filter(|interface| !interface.is_loopback())
+        .map(|interface| interface.ip())
+        .collect();
+
+    addresses
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or(addresses.first())
+        .copied()
+}
+
+fn fallback_ip(listen_ip: IpAddr) -> IpAddr {
+    if !listen_ip.is_unspecified() {
+        return listen_ip;
+    }
+
+    interface_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
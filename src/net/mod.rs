@@ -1 +1,4 @@
 pub mod chat;
+pub mod codec;
+pub mod discovery;
+pub mod transport;
@@ -0,0 +1,40 @@
+use super::chat::ConnectionPool;
+use crate::utils::message::Envelope;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+// Where the messages a node sends on its own account go, new blocks and pings. Replies go back
+// on the connection the request came in on.
+pub trait Transport: Send + Sync {
+    // Hands `envelope` over for every connected peer without waiting for it to be sent
+    fn broadcast(&self, envelope: Envelope);
+}
+
+// The TCP connections of a `ConnectionPool`. Envelopes go out one at a time in the order they
+// were broadcast, so a peer never gets block n + 1 before block n from us.
+pub struct PoolTransport {
+    sender: mpsc::UnboundedSender<Envelope>,
+}
+
+impl PoolTransport {
+    pub fn new(pool: Arc<Mutex<ConnectionPool>>) -> PoolTransport {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Envelope>();
+        tokio::spawn(async move {
+            while let Some(envelope) = receiver.recv().await {
+                // the pool shares its peers, a copy sends without holding the pool locked
+                let pool = pool.lock().await.clone();
+                pool.broadcast(None, &envelope).await;
+            }
+        });
+
+        PoolTransport { sender }
+    }
+}
+
+impl Transport for PoolTransport {
+    fn broadcast(&self, envelope: Envelope) {
+        if self.sender.send(envelope).is_err() {
+            log::error!("Broadcast dropped, the sending task is gone");
+        }
+    }
+}
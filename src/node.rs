@@ -0,0 +1,198 @@
+use crate::{
+    blockchain::{init::Blockchain, mempool::Mempool, proposal::ProposalState},
+    http_server::{router, AppState, Rejections},
+    net::{
+        chat::{connect_to_peer, handle_connection, ConnectionPool},
+        transport::{PoolTransport, Transport},
+    },
+    p2p::{bft::Bft, P2PProtocol},
+    utils::clock::{Clock, SystemClock},
+};
+use axum::extract::ws::Message;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, Mutex},
+};
+use tower_http::cors::{Any, CorsLayer};
+
+// transactions received but not yet included in a block
+pub type SharedMempool = Arc<Mutex<Mempool>>;
+
+// every proposal the node knows of, keyed by reasoning hash
+pub type Proposals = Arc<Mutex<HashMap<String, ProposalState>>>;
+
+// Everything one node owns. Nothing is process-wide, so several nodes can run side by side in
+// one process. Clones share the same state.
+#[derive(Clone)]
+pub struct Node {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+
+    pub mempool: SharedMempool,
+
+    pub proposals: Proposals,
+
+    pub connection_pool: Arc<Mutex<ConnectionPool>>,
+
+    pub p2p: Arc<Mutex<P2PProtocol>>,
+
+    pub ws_peers: Arc<Mutex<Vec<mpsc::UnboundedSender<Message>>>>,
+
+    pub rejections: Arc<Mutex<Rejections>>,
+
+    // address peers can reach this node's P2P port at
+    pub address: String,
+}
+
+impl Node {
+    // A node on the system clock that talks to its peers over TCP. With the key of a registered
+    // validator in `bft` it takes part in committing blocks.
+    pub async fn new(blockchain: Blockchain, mempool: Mempool, address: String, bft: Bft) -> Node {
+        let connection_pool = Arc::new(Mutex::new(ConnectionPool::init()));
+        let transport = Arc::new(PoolTransport::new(connection_pool.clone()));
+
+        Node::with_parts(
+            blockchain,
+            mempool,
+            address,
+            bft,
+            connection_pool,
+            Arc::new(SystemClock),
+            transport,
+        )
+        .await
+    }
+
+    // A node with its own clock and transport, the simulator swaps both for simulated ones
+    pub async fn with_parts(
+        blockchain: Blockchain,
+        mempool: Mempool,
+        address: String,
+        bft: Bft,
+        connection_pool: Arc<Mutex<ConnectionPool>>,
+        clock: Arc<dyn Clock>,
+        transport: Arc<dyn Transport>,
+    ) -> Node {
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let mempool = Arc::new(Mutex::new(mempool));
+        let proposals = Proposals::default();
+        let ws_peers = Arc::new(Mutex::new(Vec::new()));
+
+        let p2p = P2PProtocol::new(
+            blockchain.clone(),
+            mempool.clone(),
+            proposals.clone(),
+            connection_pool.clone(),
+            address.clone(),
+            clock,
+            transport,
+            ws_peers.clone(),
+            bft,
+        )
+        .await;
+
+        Node {
+            blockchain,
+            mempool,
+            proposals,
+            connection_pool,
+            p2p: Arc::new(Mutex::new(p2p)),
+            ws_peers,
+            rejections: Arc::new(Mutex::new(Rejections::default())),
+            address,
+        }
+    }
+
+    pub fn app_state(&self) -> AppState {
+        AppState {
+            pool: self.connection_pool.clone(),
+            p2p: self.p2p.clone(),
+            blockchain: self.blockchain.clone(),
+            mempool: self.mempool.clone(),
+            ws_peers: self.ws_peers.clone(),
+            rejections: self.rejections.clone(),
+        }
+    }
+
+    // Expires proposals past their deadline, runs the timeouts of the commit protocol and tells
+    // peers our height now and then, so the ones that fell behind catch up
+    pub fn spawn_maintenance(&self) {
+        let p2p = self.p2p.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(100));
+            loop {
+                interval.tick().await;
+                p2p.lock().await.tick().await;
+            }
+        });
+
+        let p2p = self.p2p.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                p2p.lock().await.expire_proposals().await;
+            }
+        });
+
+        let node = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(30));
+            loop {
+                interval.tick().await;
+                node.ping_peers().await;
+            }
+        });
+    }
+
+    pub async fn ping_peers(&self) {
+        let p2p = self.p2p.lock().await;
+        let ping = p2p.ping().await;
+        p2p.transport.broadcast(p2p.envelope(ping));
+    }
+
+    // Keeps a connection to `peer` open, dialing again 10 seconds after it drops
+    pub fn spawn_peer(&self, peer: String) {
+        let node = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = node.connect(&peer).await {
+                    log::warn!("Connection to peer {} failed: {:?}", peer, e);
+                }
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        });
+    }
+
+    // Dials `peer` and runs the connection until it is closed
+    pub async fn connect(&self, peer: &str) -> Result<(), Box<dyn std::error::Error>> {
+        connect_to_peer(self.connection_pool.clone(), peer, self.p2p.clone()).await
+    }
+
+    pub async fn serve_http(self, listener: TcpListener) -> std::io::Result<()> {
+        let cors = CorsLayer::new()
+            .allow_origin(Any) // during dev
+            .allow_methods(Any)
+            .allow_headers(Any);
+
+        log::info!("HTTP listening on {}", listener.local_addr()?);
+        axum::serve(listener, router(self.app_state()).layer(cors)).await
+    }
+
+    pub async fn serve_p2p(self, listener: TcpListener) -> std::io::Result<()> {
+        log::info!("P2P TCP listening on {}", listener.local_addr()?);
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            log::info!("P2P connection from {}", addr);
+
+            let pool = self.connection_pool.clone();
+            let p2p = self.p2p.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(pool, stream, p2p).await {
+                    log::error!("TCP error: {:?}", e);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,738 @@
+// The commit protocol of the validators, after Tendermint. Each height is decided in rounds. In
+// a round the validator whose turn it is proposes a block, then every validator prevotes and
+// precommits, for the block or for no block. A block is final once more than 2/3 of the
+// validators precommitted it in the same round, their precommits become its commit certificate.
+// Rounds that fail run into timeouts and the next validator proposes.
+
+use super::P2PProtocol;
+use crate::{
+    blockchain::{
+        block::Block,
+        commit::{quorum, BlockProposal, BlockVote, CommitCertificate, VoteKind},
+        init::Blockchain,
+    },
+    utils::{
+        crypto::{sign_vote, verify_block_signature, verify_vote, KeyPair},
+        message::MessageType,
+    },
+};
+use axum::extract::ws::Message;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// upper bound on the proposals and votes of one validator for the next height kept until we get
+// there, so no validator can crowd out the messages of the others
+const MAX_BUFFERED_PER_VALIDATOR: usize = 64;
+
+// How long each step of a round waits before giving up on it
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BftTimeouts {
+    // for the proposal of the round, then the node prevotes for no block
+    pub propose_ms: u64,
+
+    // for more prevotes once more than 2/3 of the validators prevoted without agreeing, then the
+    // node precommits for no block
+    pub prevote_ms: u64,
+
+    // for more precommits once more than 2/3 of the validators precommitted without agreeing,
+    // then the next round starts
+    pub precommit_ms: u64,
+
+    // added to each timeout per round, so rounds get long enough for a slow network
+    pub increase_ms: u64,
+
+    // the proposal and the node's own votes of the current round are sent again this often, for
+    // the peers that missed them
+    pub rebroadcast_ms: u64,
+}
+
+impl Default for BftTimeouts {
+    fn default() -> Self {
+        BftTimeouts {
+            propose_ms: 3_000,
+            prevote_ms: 1_000,
+            precommit_ms: 1_000,
+            increase_ms: 500,
+            rebroadcast_ms: 1_000,
+        }
+    }
+}
+
+impl BftTimeouts {
+    fn timeout(&self, step: RoundStep, round: u32) -> Duration {
+        let base = match step {
+            RoundStep::Propose => self.propose_ms,
+            RoundStep::Prevote => self.prevote_ms,
+            RoundStep::Precommit => self.precommit_ms,
+        };
+        Duration::milliseconds((base + self.increase_ms * round as u64) as i64)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RoundStep {
+    Propose,
+
+    Prevote,
+
+    Precommit,
+}
+
+// Where a node is in deciding the next block. Nodes that are not validators follow along
+// without sending anything, so they commit blocks as soon as the validators do.
+pub struct Bft {
+    // key of the validator this node votes as, `None` for a node that only follows
+    pub key: Option<KeyPair>,
+
+    pub timeouts: BftTimeouts,
+
+    // height being decided, one above the chain tip, 0 until the first message or tick
+    pub height: u32,
+
+    pub round: u32,
+
+    pub step: RoundStep,
+
+    // no rounds run at a height until there is a block to decide
+    pub active: bool,
+
+    // id of the validator `key` belongs to at this height
+    me: Option<String>,
+
+    // public keys of the validators deciding this height, by agent id
+    validators: BTreeMap<String, String>,
+
+    // round of the last precommit for a block and the block. Only a block more than 2/3 of the
+    // validators prevoted in a later round gets our prevote instead.
+    locked: Option<(u32, Block)>,
+
+    // last round more than 2/3 of the validators prevoted for a block and the block, proposed
+    // again on our turn
+    valid: Option<(u32, Block)>,
+
+    // first authentic proposal of each round and whether its block can be committed
+    proposals: BTreeMap<u32, (BlockProposal, bool)>,
+
+    // the first vote of each validator per round and kind
+    votes: BTreeMap<(u32, VoteKind), BTreeMap<String, BlockVote>>,
+
+    // timeouts running in the current round, by the step they belong to
+    timers: BTreeMap<RoundStep, DateTime<Utc>>,
+
+    // whether the prevote and precommit timeouts and the prevote quorum for the proposal were
+    // already handled in the current round, each is only acted on once
+    prevote_timer_set: bool,
+    precommit_timer_set: bool,
+    polka_seen: bool,
+
+    rebroadcast_at: Option<DateTime<Utc>>,
+
+    // proposals and votes for the height after this one, signed by a validator of this height.
+    // The first one per validator, round and kind, `None` standing for the proposal.
+    buffered: BTreeMap<(String, u32, Option<VoteKind>), MessageType>,
+}
+
+impl Bft {
+    pub fn new(key: Option<KeyPair>, timeouts: BftTimeouts) -> Bft {
+        Bft {
+            key,
+            timeouts,
+            height: 0,
+            round: 0,
+            step: RoundStep::Propose,
+            active: false,
+            me: None,
+            validators: BTreeMap::new(),
+            locked: None,
+            valid: None,
+            proposals: BTreeMap::new(),
+            votes: BTreeMap::new(),
+            timers: BTreeMap::new(),
+            prevote_timer_set: false,
+            precommit_timer_set: false,
+            polka_seen: false,
+            rebroadcast_at: None,
+            buffered: BTreeMap::new(),
+        }
+    }
+
+    // Forgets everything about the height just decided and starts on `height`, returns the
+    // messages buffered for it
+    fn reset(&mut self, height: u32, blockchain: &Blockchain) -> Vec<MessageType> {
+        let validators = blockchain.registry.validators();
+        self.me = self.key.as_ref().and_then(|key| {
+            validators
+                .iter()
+                .find(|record| record.public_key == key.public_key)
+                .map(|record| record.agent_id.clone())
+        });
+        self.validators = validators
+            .into_iter()
+            .map(|record| (record.agent_id.clone(), record.public_key.clone()))
+            .collect();
+
+        self.height = height;
+        self.round = 0;
+        self.step = RoundStep::Propose;
+        self.active = false;
+        self.locked = None;
+        self.valid = None;
+        self.proposals.clear();
+        self.votes.clear();
+        self.timers.clear();
+        self.prevote_timer_set = false;
+        self.precommit_timer_set = false;
+        self.polka_seen = false;
+        self.rebroadcast_at = None;
+
+        std::mem::take(&mut self.buffered).into_values().collect()
+    }
+
+    // Keeps a message for the next height from `validator`, whose signature on it was checked
+    fn buffer(
+        &mut self,
+        validator: &str,
+        round: u32,
+        kind: Option<VoteKind>,
+        message: MessageType,
+    ) {
+        let held = self
+            .buffered
+            .range((validator.to_string(), 0, None)..)
+            .take_while(|((sender, _, _), _)| sender == validator)
+            .count();
+        if held >= MAX_BUFFERED_PER_VALIDATOR {
+            log::warn!("Dropping message for the next height from {}", validator);
+            return;
+        }
+
+        self.buffered
+            .entry((validator.to_string(), round, kind))
+            .or_insert(message);
+    }
+
+    fn quorum(&self) -> usize {
+        quorum(self.validators.len())
+    }
+
+    fn count(&self, round: u32, kind: VoteKind, block_hash: &Option<String>) -> usize {
+        self.votes.get(&(round, kind)).map_or(0, |votes| {
+            votes
+                .values()
+                .filter(|vote| vote.block_hash == *block_hash)
+                .count()
+        })
+    }
+
+    fn total(&self, round: u32, kind: VoteKind) -> usize {
+        self.votes
+            .get(&(round, kind))
+            .map_or(0, |votes| votes.len())
+    }
+
+    // Round and block hash of a proposal more than 2/3 of the validators precommitted
+    fn decision(&self) -> Option<(u32, Option<String>)> {
+        self.proposals
+            .iter()
+            .filter(|(_, (_, valid))| *valid)
+            .map(|(round, (proposal, _))| (*round, proposal.block.hash.clone()))
+            .find(|(round, hash)| self.count(*round, VoteKind::Precommit, hash) >= self.quorum())
+    }
+
+    // Highest round above ours that enough validators already vote in that one of them is honest
+    fn round_to_skip_to(&self) -> Option<u32> {
+        let needed = self.validators.len() + 1 - self.quorum();
+        let mut voters: BTreeMap<u32, BTreeSet<&str>> = BTreeMap::new();
+        for ((round, _), votes) in self.votes.range((self.round + 1, VoteKind::Prevote)..) {
+            voters
+                .entry(*round)
+                .or_default()
+                .extend(votes.keys().map(String::as_str));
+        }
+
+        voters
+            .into_iter()
+            .rev()
+            .find(|(_, voters)| voters.len() >= needed)
+            .map(|(round, _)| round)
+    }
+}
+
+impl P2PProtocol {
+    // Runs the timeouts of the commit protocol, starts deciding a height once an accepted
+    // proposal waits for a block and resends what peers may have missed
+    pub async fn tick(&self) {
+        let mut bft = self.bft.lock().await;
+        self.sync_height(&mut bft).await;
+        self.activate_if_pending(&mut bft).await;
+        if !bft.active {
+            return;
+        }
+
+        let now = self.clock.now();
+        let due: Vec<RoundStep> = bft
+            .timers
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(step, _)| *step)
+            .collect();
+        for step in due {
+            bft.timers.remove(&step);
+            match step {
+                RoundStep::Propose if bft.step == RoundStep::Propose => {
+                    log::info!(
+                        "Round {} of block {} got no proposal",
+                        bft.round,
+                        bft.height
+                    );
+                    self.cast(&mut bft, VoteKind::Prevote, None);
+                }
+                RoundStep::Prevote if bft.step == RoundStep::Prevote => {
+                    self.cast(&mut bft, VoteKind::Precommit, None);
+                }
+                RoundStep::Precommit => {
+                    let round = bft.round + 1;
+                    self.start_round(&mut bft, round).await;
+                }
+                _ => {}
+            }
+        }
+
+        // the block may have become available after the round started
+        self.try_propose(&mut bft).await;
+
+        if bft.rebroadcast_at.is_none_or(|at| at <= now) {
+            bft.rebroadcast_at =
+                Some(now + Duration::milliseconds(bft.timeouts.rebroadcast_ms as i64));
+            self.rebroadcast(&bft);
+        }
+
+        self.advance(&mut bft).await;
+    }
+
+    // Starts deciding the next height if there is a block for it, called once a proposal is
+    // accepted
+    pub async fn start_commit(&self) {
+        let mut bft = self.bft.lock().await;
+        self.sync_height(&mut bft).await;
+        self.activate_if_pending(&mut bft).await;
+        self.advance(&mut bft).await;
+    }
+
+    pub async fn handle_proposal(&self, proposal: BlockProposal) {
+        let mut bft = self.bft.lock().await;
+        self.sync_height(&mut bft).await;
+        if self.add_proposal(&mut bft, proposal).await {
+            self.activate(&mut bft).await;
+        }
+        self.advance(&mut bft).await;
+    }
+
+    pub async fn handle_vote(&self, vote: BlockVote) {
+        let mut bft = self.bft.lock().await;
+        self.sync_height(&mut bft).await;
+        if self.add_vote(&mut bft, vote) {
+            self.activate(&mut bft).await;
+        }
+        self.advance(&mut bft).await;
+    }
+
+    // Moves on to the height above the chain tip once a block got appended, however it came in
+    async fn sync_height(&self, bft: &mut Bft) {
+        let buffered = {
+            let blockchain = self.blockchain.lock().await;
+            let height = blockchain.height() + 1;
+            if bft.height == height {
+                return;
+            }
+            bft.reset(height, &blockchain)
+        };
+
+        let mut relevant = false;
+        for message in buffered {
+            relevant |= match message {
+                MessageType::Proposal(proposal) => self.add_proposal(bft, proposal).await,
+                MessageType::Vote(vote) => self.add_vote(bft, vote),
+                _ => false,
+            };
+        }
+        if relevant {
+            self.activate(bft).await;
+        }
+    }
+
+    async fn activate_if_pending(&self, bft: &mut Bft) {
+        if bft.active {
+            return;
+        }
+        let pending = {
+            let blockchain = self.blockchain.lock().await;
+            !self.accepted_proposals(&blockchain).await.is_empty()
+        };
+        if pending {
+            self.activate(bft).await;
+        }
+    }
+
+    async fn activate(&self, bft: &mut Bft) {
+        if !bft.active {
+            bft.active = true;
+            self.start_round(bft, 0).await;
+        }
+    }
+
+    async fn start_round(&self, bft: &mut Bft, round: u32) {
+        log::info!("Starting round {} of block {}", round, bft.height);
+        bft.round = round;
+        bft.step = RoundStep::Propose;
+        bft.timers.clear();
+        bft.prevote_timer_set = false;
+        bft.precommit_timer_set = false;
+        bft.polka_seen = false;
+        self.set_timer(bft, RoundStep::Propose);
+
+        self.try_propose(bft).await;
+    }
+
+    fn set_timer(&self, bft: &mut Bft, step: RoundStep) {
+        let at = self.clock.now() + bft.timeouts.timeout(step, bft.round);
+        bft.timers.insert(step, at);
+    }
+
+    // Proposes a block if it is our turn in the current round and we have not yet: the block
+    // more than 2/3 prevoted for last, otherwise a new one for the oldest accepted proposal
+    async fn try_propose(&self, bft: &mut Bft) {
+        if !bft.active || bft.step != RoundStep::Propose || bft.proposals.contains_key(&bft.round) {
+            return;
+        }
+        let Some(key) = bft.key.clone() else {
+            return;
+        };
+
+        let blockchain = self.blockchain.lock().await;
+        if blockchain
+            .registry
+            .producer(bft.height, bft.round)
+            .is_none_or(|producer| producer.public_key != key.public_key)
+        {
+            return;
+        }
+
+        let proposal = match &bft.valid {
+            Some((valid_round, block)) => BlockProposal {
+                round: bft.round,
+                valid_round: Some(*valid_round),
+                block: block.clone(),
+            },
+            None => {
+                let Some(reasoning_hash) = self
+                    .accepted_proposals(&blockchain)
+                    .await
+                    .into_iter()
+                    .next()
+                else {
+                    return;
+                };
+                let block = match blockchain.propose_block(
+                    &*self.mempool.lock().await,
+                    &reasoning_hash,
+                    &key,
+                    bft.round,
+                    self.clock.now(),
+                ) {
+                    Ok(block) => block,
+                    Err(err) => {
+                        log::error!("Failed to propose a block: {:?}", err);
+                        return;
+                    }
+                };
+                BlockProposal {
+                    round: bft.round,
+                    valid_round: None,
+                    block,
+                }
+            }
+        };
+        let valid = blockchain.validate_proposed_block(&proposal.block).is_ok();
+        drop(blockchain);
+
+        log::info!(
+            "Proposing block {} in round {}",
+            proposal.block.index,
+            proposal.round
+        );
+        self.transport
+            .broadcast(self.envelope(MessageType::Proposal(proposal.clone())));
+        bft.proposals.insert(bft.round, (proposal, valid));
+    }
+
+    // Keeps the first authentic proposal of a round and relays it, returns whether it is for the
+    // height being decided. One for the next height is kept for later if a validator signed it.
+    async fn add_proposal(&self, bft: &mut Bft, proposal: BlockProposal) -> bool {
+        let block = &proposal.block;
+        if block.index != bft.height {
+            // who produces the next height is only known once this one is decided, for now the
+            // block has to be signed by one of the validators
+            let signed = block.hash.as_deref() == Some(block.compute_hash().as_str())
+                && bft
+                    .validators
+                    .get(&block.producer)
+                    .is_some_and(|public_key| verify_block_signature(block, public_key).is_ok());
+            if block.index == bft.height + 1 && signed {
+                let producer = block.producer.clone();
+                let round = proposal.round;
+                bft.buffer(&producer, round, None, MessageType::Proposal(proposal));
+            }
+            return false;
+        }
+
+        // a block proposed again keeps the round it was first proposed in
+        let round_matches = match proposal.valid_round {
+            None => block.round == proposal.round,
+            Some(valid_round) => valid_round < proposal.round && block.round <= valid_round,
+        };
+        if !round_matches {
+            log::warn!(
+                "Proposal for block {} in round {} names the wrong rounds",
+                block.index,
+                proposal.round
+            );
+            return false;
+        }
+        if bft.proposals.contains_key(&proposal.round) {
+            return true;
+        }
+
+        // only the producer of the block's round can have made it, anything else could keep the
+        // real proposal out
+        let blockchain = self.blockchain.lock().await;
+        let authentic = block.hash.as_deref() == Some(block.compute_hash().as_str())
+            && blockchain
+                .registry
+                .producer(block.index, block.round)
+                .is_some_and(|producer| {
+                    producer.agent_id == block.producer
+                        && verify_block_signature(block, &producer.public_key).is_ok()
+                });
+        if !authentic {
+            log::warn!(
+                "Proposal for block {} in round {} is not signed by its producer",
+                block.index,
+                proposal.round
+            );
+            return false;
+        }
+
+        let valid = match blockchain.validate_proposed_block(block) {
+            Ok(()) => true,
+            Err(err) => {
+                log::warn!("Proposed block {} is invalid: {:?}", block.index, err);
+                false
+            }
+        };
+        self.relay(MessageType::Proposal(proposal.clone()));
+        bft.proposals.insert(proposal.round, (proposal, valid));
+        true
+    }
+
+    // Keeps the first vote of a validator per round and kind and relays it, returns whether it
+    // is for the height being decided. One for the next height is kept for later.
+    fn add_vote(&self, bft: &mut Bft, vote: BlockVote) -> bool {
+        if vote.height != bft.height && vote.height != bft.height + 1 {
+            return false;
+        }
+
+        let Some(public_key) = bft.validators.get(&vote.validator) else {
+            log::warn!("Vote from {} who is not a validator", vote.validator);
+            return false;
+        };
+        if let Err(err) = verify_vote(&vote, public_key) {
+            log::warn!("Vote from {} rejected: {:?}", vote.validator, err);
+            return false;
+        }
+
+        if vote.height != bft.height {
+            let validator = vote.validator.clone();
+            let (round, kind) = (vote.round, vote.kind);
+            bft.buffer(&validator, round, Some(kind), MessageType::Vote(vote));
+            return false;
+        }
+
+        let votes = bft.votes.entry((vote.round, vote.kind)).or_default();
+        if !votes.contains_key(&vote.validator) {
+            self.relay(MessageType::Vote(vote.clone()));
+            votes.insert(vote.validator.clone(), vote);
+        }
+        true
+    }
+
+    // Votes in the current round and moves on to the step after it. Only a validator sends the
+    // vote, a follower just takes the step.
+    fn cast(&self, bft: &mut Bft, kind: VoteKind, block_hash: Option<String>) {
+        bft.step = match kind {
+            VoteKind::Prevote => RoundStep::Prevote,
+            VoteKind::Precommit => RoundStep::Precommit,
+        };
+
+        let (Some(key), Some(me)) = (&bft.key, &bft.me) else {
+            return;
+        };
+        let mut vote = BlockVote {
+            kind,
+            height: bft.height,
+            round: bft.round,
+            block_hash,
+            validator: me.clone(),
+            signature: String::new(),
+        };
+        if let Err(err) = sign_vote(&mut vote, key) {
+            log::error!("Failed to sign {:?}: {:?}", kind, err);
+            return;
+        }
+
+        self.transport
+            .broadcast(self.envelope(MessageType::Vote(vote.clone())));
+        bft.votes
+            .entry((vote.round, kind))
+            .or_default()
+            .insert(vote.validator.clone(), vote);
+    }
+
+    fn rebroadcast(&self, bft: &Bft) {
+        let Some(me) = &bft.me else {
+            return;
+        };
+
+        if let Some((proposal, _)) = bft.proposals.get(&bft.round) {
+            self.transport
+                .broadcast(self.envelope(MessageType::Proposal(proposal.clone())));
+        }
+        for kind in [VoteKind::Prevote, VoteKind::Precommit] {
+            if let Some(vote) = bft
+                .votes
+                .get(&(bft.round, kind))
+                .and_then(|votes| votes.get(me))
+            {
+                self.transport
+                    .broadcast(self.envelope(MessageType::Vote(vote.clone())));
+            }
+        }
+    }
+
+    // Applies the rules of the protocol until none of them changes anything
+    async fn advance(&self, bft: &mut Bft) {
+        while bft.active && self.apply_rule(bft).await {}
+    }
+
+    async fn apply_rule(&self, bft: &mut Bft) -> bool {
+        if let Some((round, block_hash)) = bft.decision() {
+            self.commit(bft, round, block_hash).await;
+            return true;
+        }
+
+        if let Some(round) = bft.round_to_skip_to() {
+            self.start_round(bft, round).await;
+            return true;
+        }
+
+        let round = bft.round;
+        let quorum = bft.quorum();
+        let proposal = bft.proposals.get(&round).cloned();
+
+        if bft.step == RoundStep::Propose
+            && let Some((proposal, valid)) = &proposal
+        {
+            let block_hash = &proposal.block.hash;
+            let acceptable = match proposal.valid_round {
+                None => Some(
+                    bft.locked
+                        .as_ref()
+                        .is_none_or(|(_, locked)| locked.hash == *block_hash),
+                ),
+                Some(valid_round)
+                    if bft.count(valid_round, VoteKind::Prevote, block_hash) >= quorum =>
+                {
+                    Some(bft.locked.as_ref().is_none_or(|(locked_round, locked)| {
+                        *locked_round <= valid_round || locked.hash == *block_hash
+                    }))
+                }
+                Some(_) => None,
+            };
+            if let Some(acceptable) = acceptable {
+                let vote = (*valid && acceptable).then(|| block_hash.clone()).flatten();
+                self.cast(bft, VoteKind::Prevote, vote);
+                return true;
+            }
+        }
+
+        if bft.step == RoundStep::Prevote
+            && !bft.prevote_timer_set
+            && bft.total(round, VoteKind::Prevote) >= quorum
+        {
+            bft.prevote_timer_set = true;
+            self.set_timer(bft, RoundStep::Prevote);
+            return true;
+        }
+
+        if bft.step >= RoundStep::Prevote
+            && !bft.polka_seen
+            && let Some((proposal, true)) = &proposal
+            && bft.count(round, VoteKind::Prevote, &proposal.block.hash) >= quorum
+        {
+            bft.polka_seen = true;
+            if bft.step == RoundStep::Prevote {
+                bft.locked = Some((round, proposal.block.clone()));
+                self.cast(bft, VoteKind::Precommit, proposal.block.hash.clone());
+            }
+            bft.valid = Some((round, proposal.block.clone()));
+            return true;
+        }
+
+        if bft.step == RoundStep::Prevote && bft.count(round, VoteKind::Prevote, &None) >= quorum {
+            self.cast(bft, VoteKind::Precommit, None);
+            return true;
+        }
+
+        if !bft.precommit_timer_set && bft.total(round, VoteKind::Precommit) >= quorum {
+            bft.precommit_timer_set = true;
+            self.set_timer(bft, RoundStep::Precommit);
+            return true;
+        }
+
+        false
+    }
+
+    // Appends the block precommitted in `round` with its commit certificate and moves on to the
+    // next height
+    async fn commit(&self, bft: &mut Bft, round: u32, block_hash: Option<String>) {
+        let precommits = bft.votes[&(round, VoteKind::Precommit)]
+            .values()
+            .filter(|vote| vote.block_hash == block_hash)
+            .cloned()
+            .collect();
+        let Some((proposal, valid)) = bft.proposals.get_mut(&round) else {
+            return;
+        };
+        let mut block = proposal.block.clone();
+        block.commit = Some(CommitCertificate { round, precommits });
+
+        log::info!("Committing block {} from round {}", block.index, round);
+        if let Err(err) = self.import_block(block.clone()).await {
+            log::error!("Failed to commit block {}: {:?}", block.index, err);
+            *valid = false;
+            return;
+        }
+
+        self.transport
+            .broadcast(self.envelope(MessageType::Block(block.clone())));
+        let msg = serde_json::to_string_pretty(&block).unwrap();
+        for peer in self.ws_peers.lock().await.iter() {
+            let _ = peer.send(Message::Text(msg.clone().into()));
+        }
+
+        self.sync_height(bft).await;
+        self.activate_if_pending(bft).await;
+    }
+}
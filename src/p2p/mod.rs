@@ -1,44 +1,228 @@
+pub mod bft;
+
 use crate::{
-    blockchain::init::Blockchain,
-    net::chat::{Client, ConnectionPool},
-    server::handler::Server,
-    types::blockchain::{ActionType, Ping, Transaction, TransactionMessage},
-    utils::message::create_block_message,
+    blockchain::{
+        block::Block,
+        init::{active_vote, Blockchain},
+        proposal::ProposalState,
+        registry::AgentRegistry,
+    },
+    net::{chat::ConnectionPool, codec::write_message, transport::Transport},
+    node::{Proposals, SharedMempool},
+    types::{
+        blockchain::{ActionType, PeerAddr, Ping, ProposalStatus, Transaction, TransactionMessage},
+        error::ErrorTypes,
+    },
+    utils::{
+        clock::Clock,
+        crypto::verify_transaction,
+        hasher::transaction_hash,
+        message::{Envelope, MessageType},
+    },
 };
 use axum::extract::ws::Message;
-use once_cell::sync::Lazy;
-use std::{collections::HashMap, sync::Arc};
+use bft::Bft;
+use std::sync::Arc;
 use tokio::{
-    io::AsyncWriteExt,
     net::tcp::OwnedWriteHalf,
     sync::{mpsc, Mutex},
 };
 
-static PROPOSAL_OWNERS: Lazy<Arc<Mutex<HashMap<String, String>>>> =
-    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
-
-pub static CURRENT_TRANSACTIONS: once_cell::sync::Lazy<Arc<tokio::sync::Mutex<Vec<Transaction>>>> =
-    once_cell::sync::Lazy::new(|| Arc::new(tokio::sync::Mutex::new(Vec::new())));
+// upper bound on the blocks sent in one `Blocks` reply, the requester asks again for the rest
+pub const MAX_BLOCKS_PER_MESSAGE: u32 = 100;
 
+// The protocol logic of a node, working on the state handles of its `Node`
 pub struct P2PProtocol {
-    pub server: Arc<Mutex<Server>>,
     pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: SharedMempool,
+    pub proposals: Proposals,
     pub connection_pool: Arc<Mutex<ConnectionPool>>,
+
+    // advertised in the meta of every message this node sends
+    pub address: String,
+
+    pub clock: Arc<dyn Clock>,
+
+    // carries the blocks, proposals and votes of this node to its peers
+    pub transport: Arc<dyn Transport>,
+
+    // websocket clients told about every block this node commits
+    pub ws_peers: Arc<Mutex<Vec<mpsc::UnboundedSender<Message>>>>,
+
+    pub bft: Mutex<Bft>,
 }
 
 impl P2PProtocol {
-    pub async fn new(server: Arc<Mutex<Server>>) -> Self {
-        let server_clone = server.clone();
-        let server_lock = server_clone.lock().await;
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        blockchain: Arc<Mutex<Blockchain>>,
+        mempool: SharedMempool,
+        proposals: Proposals,
+        connection_pool: Arc<Mutex<ConnectionPool>>,
+        address: String,
+        clock: Arc<dyn Clock>,
+        transport: Arc<dyn Transport>,
+        ws_peers: Arc<Mutex<Vec<mpsc::UnboundedSender<Message>>>>,
+        bft: Bft,
+    ) -> Self {
+        // proposals already in the chain are finalized, everything else died with the last run
+        {
+            let blockchain = blockchain.lock().await;
+            let mut proposals = proposals.lock().await;
+            for block in blockchain.blocks.iter() {
+                for tx in block
+                    .transactions
+                    .iter()
+                    .filter(|tx| tx.action_type == ActionType::ProposeUpdate)
+                {
+                    proposals.insert(
+                        tx.reasoning_hash.clone(),
+                        ProposalState::finalized(tx, block.index, block.timestamp),
+                    );
+                }
+            }
+        }
 
         Self {
-            server,
-            blockchain: server_lock.blockchain.clone(),
-            connection_pool: server_lock.connection_pool.clone(),
+            blockchain,
+            mempool,
+            proposals,
+            connection_pool,
+            address,
+            clock,
+            transport,
+            ws_peers,
+            bft: Mutex::new(bft),
+        }
+    }
+
+    pub async fn proposal_status(&self, reasoning_hash: &str) -> Option<ProposalState> {
+        self.proposals.lock().await.get(reasoning_hash).cloned()
+    }
+
+    pub async fn proposals(&self) -> Vec<ProposalState> {
+        let mut proposals: Vec<ProposalState> =
+            self.proposals.lock().await.values().cloned().collect();
+        proposals.sort_by_key(|proposal| proposal.proposed_at);
+        proposals
+    }
+
+    // Expires every open proposal whose deadline has passed and drops its pending transactions
+    pub async fn expire_proposals(&self) {
+        let height = self.blockchain.lock().await.height();
+        let now = self.clock.now();
+
+        let expired: Vec<String> = self
+            .proposals
+            .lock()
+            .await
+            .values_mut()
+            .filter_map(|proposal| {
+                proposal
+                    .expire_if_due(height, now)
+                    .then(|| proposal.reasoning_hash.clone())
+            })
+            .collect();
+
+        for reasoning_hash in expired {
+            self.discard_proposal(&reasoning_hash).await;
+        }
+
+        let dropped = self.mempool.lock().await.expire(now);
+        self.forget_proposals(dropped).await;
+    }
+
+    // Drops every pending transaction of a proposal that will never be finalized
+    pub async fn discard_proposal(&self, reasoning_hash: &str) {
+        self.mempool.lock().await.remove_proposal(reasoning_hash);
+    }
+
+    // Expires the open and accepted proposals the mempool dropped, without their proposal
+    // transaction they can never be finalized
+    async fn forget_proposals(&self, dropped: Vec<String>) {
+        let mut proposals = self.proposals.lock().await;
+        for reasoning_hash in dropped {
+            if let Some(proposal) = proposals.get_mut(&reasoning_hash)
+                && (proposal.is_open() || proposal.status == ProposalStatus::Accepted)
+            {
+                log::warn!("Proposal {} dropped from the mempool", reasoning_hash);
+                let _ = proposal.transition(ProposalStatus::Expired);
+            }
+        }
+    }
+
+    // Puts a validated transaction into the mempool
+    async fn admit(&self, tx: Transaction) -> Result<(), ErrorTypes> {
+        let dropped = self.mempool.lock().await.insert(tx, self.clock.now())?;
+        self.forget_proposals(dropped).await;
+        Ok(())
+    }
+
+    // Drops what the last committed block made invalid from the mempool, then decides the
+    // accepted proposals again on the votes that are left
+    async fn revalidate_mempool(&self, blockchain: &Blockchain) {
+        let dropped = self.mempool.lock().await.revalidate(blockchain);
+        self.forget_proposals(dropped).await;
+        self.reconsider_accepted(blockchain).await;
+    }
+
+    // An accepted proposal only stays accepted while the pending votes still accept it. Without
+    // them it goes back to voting, or is rejected if the votes left reject it.
+    async fn reconsider_accepted(&self, blockchain: &Blockchain) {
+        let mut rejected = Vec::new();
+        {
+            let mempool = self.mempool.lock().await;
+            let mut proposals = self.proposals.lock().await;
+            for proposal in proposals
+                .values_mut()
+                .filter(|proposal| proposal.status == ProposalStatus::Accepted)
+            {
+                let next = match blockchain.decide_proposal(&mempool, &proposal.reasoning_hash) {
+                    Some(ActionType::VoteAccept) => continue,
+                    Some(ActionType::VoteReject) => ProposalStatus::Rejected,
+                    _ => ProposalStatus::Voting,
+                };
+                log::warn!(
+                    "Proposal {} lost the votes accepting it",
+                    proposal.reasoning_hash
+                );
+                let _ = proposal.transition(next.clone());
+                if next == ProposalStatus::Rejected {
+                    rejected.push(proposal.reasoning_hash.clone());
+                }
+            }
+        }
+
+        for reasoning_hash in rejected {
+            self.discard_proposal(&reasoning_hash).await;
         }
     }
 
-    fn validate_proposal(tx: &Transaction) -> Result<(), String> {
+    // Moves a proposal to `next`, failing if it is unknown, past its deadline or not in a
+    // state that allows the move
+    async fn advance_proposal(
+        &self,
+        reasoning_hash: &str,
+        next: ProposalStatus,
+    ) -> Result<ProposalState, ErrorTypes> {
+        self.expire_proposals().await;
+
+        let mut proposals = self.proposals.lock().await;
+        let proposal = proposals.get_mut(reasoning_hash).ok_or_else(|| {
+            ErrorTypes::UnknownProposal(format!("Proposal {} does not exist", reasoning_hash))
+        })?;
+        proposal.transition(next)?;
+
+        Ok(proposal.clone())
+    }
+
+    fn validate_proposal(tx: &Transaction, registry: &AgentRegistry) -> Result<(), ErrorTypes> {
+        registry.authorize(tx)?;
+
+        Self::validate_proposal_fields(tx).map_err(ErrorTypes::InvalidProposal)
+    }
+
+    fn validate_proposal_fields(tx: &Transaction) -> Result<(), String> {
         if tx.agent_id.trim().is_empty() {
             return Err("Agent ID is empty".to_string());
         }
@@ -52,194 +236,488 @@ impl P2PProtocol {
         }
 
         if tx.payload.description.trim().is_empty() {
-            return Err("Proposal description is empty".to_string());
+            Err("Proposal description is empty".to_string())
         } else {
             Ok(())
         }
     }
 
-    pub async fn handle_transaction(
-        &self,
-        msg: &str,
-        writer: Option<Arc<Mutex<OwnedWriteHalf>>>,
-        ws_peers: Option<Arc<Mutex<Vec<mpsc::UnboundedSender<Message>>>>>,
-    ) {
-        match serde_json::from_str::<TransactionMessage>(msg) {
-            Ok(tx_msg) => {
+    // Rejects duplicates and replays: the nonce has to be above every nonce the agent has used
+    // so far, in the chain or still pending
+    async fn check_nonce(&self, tx: &Transaction) -> Result<(), ErrorTypes> {
+        let chain_nonce = self.blockchain.lock().await.index.last_nonce(&tx.agent_id);
+        let pending_nonce = self.mempool.lock().await.last_nonce(&tx.agent_id);
+
+        match chain_nonce.max(pending_nonce) {
+            Some(last) if tx.nonce <= last => Err(ErrorTypes::InvalidNonce(format!(
+                "Nonce {} of agent {} must be above its last nonce {}",
+                tx.nonce, tx.agent_id, last
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // An agent has at most one active vote per proposal. Changing it takes a vote for the other
+    // side that names the active vote in `replaces_vote`.
+    async fn check_vote_change(&self, tx: &Transaction) -> Result<(), ErrorTypes> {
+        let pending = self.mempool.lock().await;
+        let active = active_vote(
+            pending.proposal(&tx.reasoning_hash),
+            &tx.agent_id,
+            &tx.reasoning_hash,
+        );
+
+        match (active, &tx.payload.replaces_vote) {
+            (None, None) => Ok(()),
+            (None, Some(replaced)) => Err(ErrorTypes::InvalidVote(format!(
+                "Agent {} has no vote on proposal {} for {} to replace",
+                tx.agent_id, tx.reasoning_hash, replaced
+            ))),
+            (Some(active), None) => Err(ErrorTypes::InvalidVote(format!(
+                "Agent {} already voted on proposal {}, set replaces_vote to {} to change it",
+                tx.agent_id,
+                tx.reasoning_hash,
+                transaction_hash(active)
+            ))),
+            (Some(active), Some(replaced)) => {
+                let active_id = transaction_hash(active);
+                if *replaced != active_id {
+                    return Err(ErrorTypes::InvalidVote(format!(
+                        "Vote {} is not the active vote {} of agent {} on proposal {}",
+                        replaced, active_id, tx.agent_id, tx.reasoning_hash
+                    )));
+                }
+                if active.action_type == tx.action_type {
+                    return Err(ErrorTypes::InvalidVote(format!(
+                        "Agent {} already cast {:?} on proposal {}",
+                        tx.agent_id, tx.action_type, tx.reasoning_hash
+                    )));
+                }
+
                 log::info!(
-                    "Received transaction from agent: {}\n",
-                    tx_msg.payload.agent_id
+                    "Agent {} changes its vote on {} to {:?}, replacing {}",
+                    tx.agent_id,
+                    tx.reasoning_hash,
+                    tx.action_type,
+                    active_id
                 );
-                log::info!("Action type: {:?}", tx_msg.payload.action_type);
-                log::info!("Reasoning hash: {}", tx_msg.payload.reasoning_hash);
-                log::info!("Description: {}", tx_msg.payload.payload.description);
-
-                match tx_msg.payload.action_type {
-                    crate::types::blockchain::ActionType::ProposeUpdate => {
-                        log::info!("ProposeUpdate: {:?}", tx_msg);
-
-                        if let Err(err) = Self::validate_proposal(&tx_msg.payload) {
-                            log::warn!("Invalid proposal: {:?}", err);
-                            return;
-                        }
-
-                        PROPOSAL_OWNERS.lock().await.insert(
-                            tx_msg.payload.reasoning_hash.clone(),
-                            tx_msg.payload.agent_id.clone(),
-                        );
-
-                        CURRENT_TRANSACTIONS
-                            .lock()
-                            .await
-                            .push(tx_msg.payload.clone());
-                        let pool = self.connection_pool.lock().await;
-                        for client in pool.clients.lock().await.iter() {
-                            let mut writer = client.writer.lock().await;
-                            let message = serde_json::to_string(&tx_msg).unwrap();
-                            let _ = writer.write_all(message.as_bytes()).await;
-                        }
-
-                        log::info!("Proposal broadcasted to peers.");
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn handle_transaction(&self, tx_msg: TransactionMessage) -> Result<(), ErrorTypes> {
+        if let Err(err) = verify_transaction(&tx_msg.payload) {
+            log::warn!(
+                "Rejected transaction from agent {}: {:?}",
+                tx_msg.payload.agent_id,
+                err
+            );
+            return Err(err);
+        }
+
+        // peers relay what is new to them, so the same transaction can come in more than once
+        let id = transaction_hash(&tx_msg.payload);
+        if self.mempool.lock().await.contains(&id)
+            || self.blockchain.lock().await.find_transaction(&id).is_some()
+        {
+            return Err(ErrorTypes::DuplicateTransaction(format!(
+                "Transaction {} is already known",
+                id
+            )));
+        }
+
+        self.check_nonce(&tx_msg.payload).await?;
+
+        log::info!(
+            "Received transaction from agent: {}\n",
+            tx_msg.payload.agent_id
+        );
+        log::info!("Action type: {:?}", tx_msg.payload.action_type);
+        log::info!("Reasoning hash: {}", tx_msg.payload.reasoning_hash);
+        log::info!("Description: {}", tx_msg.payload.payload.description);
+
+        match tx_msg.payload.action_type {
+            crate::types::blockchain::ActionType::ProposeUpdate => {
+                log::info!("ProposeUpdate: {:?}", tx_msg);
+
+                let height = {
+                    let blockchain = self.blockchain.lock().await;
+                    if let Err(err) = Self::validate_proposal(&tx_msg.payload, &blockchain.registry)
+                    {
+                        log::warn!("Invalid proposal: {:?}", err);
+                        return Err(err);
                     }
+                    blockchain.height()
+                };
 
-                    crate::types::blockchain::ActionType::VoteAccept
-                    | crate::types::blockchain::ActionType::VoteReject => {
-                        let owners = PROPOSAL_OWNERS.lock().await;
-                        if let Some(owner) = owners.get(&tx_msg.payload.reasoning_hash) {
-                            if owner == &tx_msg.payload.agent_id {
-                                log::warn!(
-                                    "Agent {} attempted to vote on its own proposal {}. Ignoring.",
-                                    tx_msg.payload.agent_id,
-                                    tx_msg.payload.reasoning_hash
-                                );
-                                return;
-                            }
-                        }
-                        log::info!("VoteAccept: {:?}", tx_msg);
-
-                        CURRENT_TRANSACTIONS.lock().await.push(tx_msg.payload);
-
-                        let verdict = self
-                            .blockchain
-                            .lock()
-                            .await
-                            .proof_of_work(
-                                self.connection_pool.lock().await.clients.lock().await.len() as u32,
-                            )
-                            .await;
-
-                        log::warn!("Verdict: {:?}", verdict);
-
-                        if verdict == Some(ActionType::VoteAccept) {
-                            log::info!("Adding new block!\n");
-                            let (block, _blockchain) =
-                                self.blockchain.lock().await.add_new_block().await;
-                            let message = serde_json::to_string_pretty(&block).unwrap();
-                            let pool = self.connection_pool.lock().await;
-                            for client in pool.clients.lock().await.iter() {
-                                let mut writer = client.writer.lock().await;
-                                let _ = writer.write_all(message.as_bytes()).await;
-                            }
-
-                            if let Some(ws) = ws_peers {
-                                let msg = serde_json::to_string_pretty(&block).unwrap();
-                                let peers = ws.lock().await;
-                                for peer in peers.iter() {
-                                    let _ = peer.send(Message::Text(msg.clone().into()));
-                                }
-                            }
-                        } else if verdict == Some(ActionType::VoteReject) {
-                            log::info!("Block has been rejected!\n");
-                        } else {
-                            log::warn!("Consensus not reached yet!\n");
-                        }
+                if self
+                    .proposals
+                    .lock()
+                    .await
+                    .contains_key(&tx_msg.payload.reasoning_hash)
+                {
+                    return Err(ErrorTypes::InvalidProposal(format!(
+                        "Proposal {} already exists",
+                        tx_msg.payload.reasoning_hash
+                    )));
+                }
+
+                let proposal = ProposalState::new(&tx_msg.payload, height, self.clock.now())?;
+                self.admit(tx_msg.payload.clone()).await?;
+                self.proposals
+                    .lock()
+                    .await
+                    .insert(tx_msg.payload.reasoning_hash.clone(), proposal);
+            }
+
+            crate::types::blockchain::ActionType::VoteAccept
+            | crate::types::blockchain::ActionType::VoteReject => {
+                if let Err(err) = self
+                    .blockchain
+                    .lock()
+                    .await
+                    .registry
+                    .authorize(&tx_msg.payload)
+                {
+                    log::warn!("Rejected vote: {:?}", err);
+                    return Err(err);
+                }
+
+                if let Some(proposal) = self.proposal_status(&tx_msg.payload.reasoning_hash).await
+                {
+                    if proposal.owner == tx_msg.payload.agent_id {
+                        return Err(ErrorTypes::InvalidVote(format!(
+                            "Agent {} may not vote on its own proposal {}",
+                            tx_msg.payload.agent_id, tx_msg.payload.reasoning_hash
+                        )));
                     }
-                    crate::types::blockchain::ActionType::FlagMalicious => {
-                        log::info!("FlagMalicious: {:?}\n", tx_msg);
+                    // voting reopens only if the chain drops the votes that accepted it
+                    if proposal.status == ProposalStatus::Accepted {
+                        return Err(ErrorTypes::InvalidTransition(format!(
+                            "Proposal {} is already accepted",
+                            tx_msg.payload.reasoning_hash
+                        )));
                     }
-                    crate::types::blockchain::ActionType::FinalizeBlock => {
-                        log::info!("FinalizeBlock: {:?}\n", tx_msg);
+                }
+                self.check_vote_change(&tx_msg.payload).await?;
+                log::info!("VoteAccept: {:?}", tx_msg);
+
+                let reasoning_hash = tx_msg.payload.reasoning_hash.clone();
+                self.advance_proposal(&reasoning_hash, ProposalStatus::Voting)
+                    .await?;
+                self.admit(tx_msg.payload).await?;
+
+                let verdict = self
+                    .blockchain
+                    .lock()
+                    .await
+                    .decide_proposal(&*self.mempool.lock().await, &reasoning_hash);
+
+                log::warn!("Verdict: {:?}", verdict);
+
+                if verdict == Some(ActionType::VoteAccept) {
+                    self.advance_proposal(&reasoning_hash, ProposalStatus::Accepted)
+                        .await?;
+                    // the block finalizing it is decided among the validators
+                    self.start_commit().await;
+                } else if verdict == Some(ActionType::VoteReject) {
+                    log::info!("Proposal {} has been rejected!\n", reasoning_hash);
+                    self.advance_proposal(&reasoning_hash, ProposalStatus::Rejected)
+                        .await?;
+                    self.discard_proposal(&reasoning_hash).await;
+                } else {
+                    log::warn!("Consensus not reached yet!\n");
+                }
+            }
+            crate::types::blockchain::ActionType::EvaluateUpdate => {
+                if let Err(err) = self
+                    .blockchain
+                    .lock()
+                    .await
+                    .registry
+                    .authorize(&tx_msg.payload)
+                {
+                    log::warn!("Rejected evaluation: {:?}", err);
+                    return Err(err);
+                }
+
+                self.advance_proposal(
+                    &tx_msg.payload.reasoning_hash,
+                    ProposalStatus::UnderEvaluation,
+                )
+                .await?;
+
+                log::info!("EvaluateUpdate: {:?}", tx_msg);
+                self.admit(tx_msg.payload).await?;
+            }
+            crate::types::blockchain::ActionType::RegisterAgent => {
+                if let Err(err) = self
+                    .blockchain
+                    .lock()
+                    .await
+                    .registry
+                    .validate_registration(&tx_msg.payload)
+                {
+                    log::warn!("Rejected registration: {:?}", err);
+                    return Err(err);
+                }
+
+                if self.mempool.lock().await.iter().any(|tx| {
+                    tx.action_type == ActionType::RegisterAgent
+                        && tx.agent_id == tx_msg.payload.agent_id
+                }) {
+                    return Err(ErrorTypes::InvalidRegistration(format!(
+                        "Registration of agent {} is already pending",
+                        tx_msg.payload.agent_id
+                    )));
+                }
+
+                // takes effect once the registration is included in a block
+                log::info!("RegisterAgent: {:?}", tx_msg);
+                self.admit(tx_msg.payload).await?;
+            }
+            crate::types::blockchain::ActionType::FlagMalicious
+            | crate::types::blockchain::ActionType::FinalizeBlock => {
+                log::info!("{:?}: {:?}\n", tx_msg.payload.action_type, tx_msg);
+                return Err(ErrorTypes::UnsupportedAction(format!(
+                    "{:?} transactions are not processed yet",
+                    tx_msg.payload.action_type
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Accepted proposals still waiting for a block, oldest first, as long as their proposal
+    // transaction and the votes accepting it are still pending. A proposal losing them is moved
+    // out of `Accepted` when the mempool is revalidated, see `reconsider_accepted`.
+    async fn accepted_proposals(&self, blockchain: &Blockchain) -> Vec<String> {
+        let mut accepted: Vec<ProposalState> = self
+            .proposals
+            .lock()
+            .await
+            .values()
+            .filter(|proposal| proposal.status == ProposalStatus::Accepted)
+            .cloned()
+            .collect();
+        accepted.sort_by(|a, b| {
+            (a.proposed_at, &a.reasoning_hash).cmp(&(b.proposed_at, &b.reasoning_hash))
+        });
+
+        let mempool = self.mempool.lock().await;
+        accepted
+            .into_iter()
+            .map(|proposal| proposal.reasoning_hash)
+            .filter(|reasoning_hash| {
+                mempool
+                    .proposal(reasoning_hash)
+                    .any(|tx| tx.action_type == ActionType::ProposeUpdate)
+                    && blockchain.decide_proposal(&mempool, reasoning_hash)
+                        == Some(ActionType::VoteAccept)
+            })
+            .collect()
+    }
+
+    pub async fn send_message(writer: &mut OwnedWriteHalf, message: &Envelope) {
+        if let Err(e) = write_message(writer, message).await {
+            log::error!("Error while sending message: {:?}", e);
+        }
+    }
+
+    pub fn envelope(&self, message: MessageType) -> Envelope {
+        Envelope::new(&self.address, message)
+    }
+
+    // Passes a message received from a peer on to all of ours, so it reaches the nodes that are
+    // not connected to where it came from. Only called the first time a message is seen, copies
+    // coming back end at the deduplication of each node.
+    pub(crate) fn relay(&self, message: MessageType) {
+        self.transport.broadcast(self.envelope(message));
+    }
+
+    // Handles a message from a peer and returns the replies for it, whatever carries them
+    pub async fn respond(&self, message: Envelope) -> Vec<MessageType> {
+        match message.message {
+            MessageType::Block(block) => self.handle_block(block).await.into_iter().collect(),
+            MessageType::GetBlocks { from, to } => vec![self.handle_get_blocks(from, to).await],
+            MessageType::Blocks { blocks, height } => self
+                .handle_blocks(blocks, height)
+                .await
+                .into_iter()
+                .collect(),
+            MessageType::Peers(peers) => self
+                .handle_peers(&message.meta.address, peers)
+                .await
+                .into_iter()
+                .collect(),
+            MessageType::Ping(ping) => self.handle_ping(ping).await,
+            MessageType::Transaction(tx_msg) => {
+                match self.handle_transaction(tx_msg.clone()).await {
+                    Ok(()) => {
+                        self.relay(MessageType::Transaction(tx_msg));
+                        Vec::new()
                     }
-                    _ => {}
+                    // a copy relayed by another peer, not an error of the sender
+                    Err(ErrorTypes::DuplicateTransaction(_)) => Vec::new(),
+                    Err(err) => vec![MessageType::Error(err)],
                 }
             }
+            MessageType::Proposal(proposal) => {
+                self.handle_proposal(proposal).await;
+                Vec::new()
+            }
+            MessageType::Vote(vote) => {
+                self.handle_vote(vote).await;
+                Vec::new()
+            }
+            MessageType::Handshake(_) => vec![MessageType::Error(ErrorTypes::UnsupportedMessage(
+                "Handshake was already completed".to_string(),
+            ))],
+            MessageType::Error(err) => {
+                log::warn!("Peer {} reported an error: {:?}", message.meta.address, err);
+                Vec::new()
+            }
+        }
+    }
+
+    // What this node tells its peers periodically, so the ones that fell behind catch up
+    pub async fn ping(&self) -> MessageType {
+        MessageType::Ping(Ping {
+            block_height: self.blockchain.lock().await.height(),
+            peer_count: self.connection_pool.lock().await.clients.lock().await.len(),
+            is_miner: false,
+        })
+    }
+
+    // Asks a peer for the blocks we are missing if it is ahead of us
+    pub async fn sync_request(&self, peer_height: u32) -> Option<MessageType> {
+        let height = self.blockchain.lock().await.height();
+
+        (peer_height > height).then(|| {
+            log::info!(
+                "Peer is at height {}, requesting blocks {}..={}",
+                peer_height,
+                height + 1,
+                peer_height
+            );
+            MessageType::GetBlocks {
+                from: height + 1,
+                to: peer_height,
+            }
+        })
+    }
 
-            Err(e) => {}
-        }
-    }
-
-    pub async fn send_message(writer: &mut OwnedWriteHalf, message: String) {
-        let _ = writer
-            .write_all(format!("\n{}\n", message).as_bytes())
-            .await;
-    }
-
-    // pub async fn handle_message(&self, writer: &mut OwnedWriteHalf, message: String) {
-    //     let message = serde_json::from_str(&message).unwrap();
-    //
-    //     match message {
-    //         MessageType::BlockMessage(block) => {
-    //             let message = serde_json::to_string(&block).unwrap();
-    //             self.handle_block(writer, message).await;
-    //         }
-    //
-    //         MessageType::PeersMessage(peer) => {
-    //             let message = serde_json::to_string(&peer).unwrap();
-    //             self.handle_block(writer, message).await;
-    //         }
-    //
-    //         MessageType::PingMessage(ping) => {
-    //             let message = serde_json::to_string(&ping).unwrap();
-    //             self.handle_ping(writer, message).await;
-    //         }
-    //
-    //         MessageType::TransactionMessage(transaction) => {
-    //             let message = serde_json::to_string(&transaction).unwrap();
-    //             self.handle_transaction(writer, message).await;
-    //         }
-    //     }
-    // }
-    //
-    // pub async fn handle_ping(&self, writer: &mut OwnedWriteHalf, message: String) {
-    //     let peers_message = {
-    //         let pool = self.connection_pool.lock().await;
-    //         let message: Ping = serde_json::from_str(&message).unwrap();
-    //         let alive_peers = pool.get_most_alive_peers(message.peer_count).await;
-    //         serde_json::to_string(&alive_peers).unwrap()
-    //     };
-    //
-    //     P2PProtocol::send_message(writer, peers_message);
-    //
-    //     let last_height = self.blockchain.lock().await.blocks.len();
-    //     let pool = self.connection_pool.lock().await;
-    //     let message: Ping = serde_json::from_str(&message).unwrap();
-    //     if message.block_height < last_height {
-    //         for block in &self
-    //             .blockchain
-    //             .lock()
-    //             .await
-    //             .blocks
-    //             .iter()
-    //             .skip(message.block_height + 1)
-    //         {
-    //             let block_message = create_block_message(ip, port, block).await;
-    //
-    //             P2PProtocol::send_message(writer, block_message);
-    //         }
-    //     } else {
-    //         None
-    //     }
-    // }
-    //
-    // pub async fn handle_block(&self, writer: &mut OwnedWriteHalf, message: String) {
-    //     todo!()
-    // }
-    //
-    // pub async fn handle_transaction(&self, writer: &mut OwnedWriteHalf, message: String) {
-    //     todo!()
-    // }
-    //
-    // pub async fn handle_peers(&self, writer: &mut OwnedWriteHalf, message: String) {
-    //     todo!()
-    // }
+    // Checks a committed block against our chain and appends it, proposals it carries are
+    // finalized. Blocks at a height we already have are left alone.
+    async fn import_block(&self, block: Block) -> Result<(), ErrorTypes> {
+        let mut blockchain = self.blockchain.lock().await;
+
+        if block.index <= blockchain.height() {
+            let ours = blockchain.blocks_in_range(block.index, block.index);
+            if ours.first().is_some_and(|ours| ours.hash != block.hash) {
+                log::warn!(
+                    "Peer block {} differs from ours, keeping our chain",
+                    block.index
+                );
+            }
+            return Ok(());
+        }
+
+        blockchain.append_block(block.clone(), &mut *self.mempool.lock().await)?;
+
+        {
+            let mut proposals = self.proposals.lock().await;
+            for tx in block
+                .transactions
+                .iter()
+                .filter(|tx| tx.action_type == ActionType::ProposeUpdate)
+            {
+                proposals.insert(
+                    tx.reasoning_hash.clone(),
+                    ProposalState::finalized(tx, block.index, block.timestamp),
+                );
+            }
+        }
+        self.revalidate_mempool(&blockchain).await;
+
+        Ok(())
+    }
+
+    pub async fn handle_ping(&self, ping: Ping) -> Vec<MessageType> {
+        log::info!(
+            "Ping: peer is at height {} with {} peer(s)",
+            ping.block_height,
+            ping.peer_count
+        );
+        let peers = self.connection_pool.lock().await.peer_addresses().await;
+        let height = self.blockchain.lock().await.height();
+
+        let mut replies = vec![MessageType::Peers(peers)];
+        if ping.block_height < height {
+            // the peer is behind, hand it the first batch it is missing
+            replies.push(self.handle_get_blocks(ping.block_height + 1, height).await);
+        } else if let Some(request) = self.sync_request(ping.block_height).await {
+            replies.push(request);
+        }
+
+        replies
+    }
+
+    pub async fn handle_block(&self, block: Block) -> Option<MessageType> {
+        log::info!("Received block {} from peer", block.index);
+        let height = self.blockchain.lock().await.height();
+
+        // we missed blocks in between, fetch them all including this one
+        if block.index > height + 1 {
+            return self.sync_request(block.index).await;
+        }
+
+        match self.import_block(block.clone()).await {
+            Ok(()) if block.index == height + 1 => {
+                self.relay(MessageType::Block(block));
+                None
+            }
+            Ok(()) => None,
+            Err(err) => Some(MessageType::Error(err)),
+        }
+    }
+
+    pub async fn handle_get_blocks(&self, from: u32, to: u32) -> MessageType {
+        let blockchain = self.blockchain.lock().await;
+        let to = to.min(from.saturating_add(MAX_BLOCKS_PER_MESSAGE - 1));
+
+        MessageType::Blocks {
+            blocks: blockchain.blocks_in_range(from, to),
+            height: blockchain.height(),
+        }
+    }
+
+    // Appends a batch of requested blocks and asks for the next one until we reach `peer_height`
+    pub async fn handle_blocks(&self, blocks: Vec<Block>, peer_height: u32) -> Option<MessageType> {
+        if blocks.is_empty() {
+            return None;
+        }
+
+        for block in blocks {
+            if let Err(err) = self.import_block(block).await {
+                log::error!("Stopped syncing on an invalid block: {:?}", err);
+                return Some(MessageType::Error(err));
+            }
+        }
+
+        self.sync_request(peer_height).await
+    }
+
+    pub async fn handle_peers(&self, address: &str, peers: Vec<PeerAddr>) -> Option<MessageType> {
+        log::info!(
+            "Peer {} knows {} peer(s): {:?}",
+            address,
+            peers.len(),
+            peers
+        );
+
+        None
+    }
 }
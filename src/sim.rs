@@ -0,0 +1,647 @@
+// Deterministic simulation of a network of nodes. The nodes run the real protocol code, but
+// their messages travel through a simulated network with seeded delays, drops and partitions,
+// which also reorders them, and their time comes from a simulated clock. Everything random is
+// drawn from one seed, so a failing run can be replayed exactly from its seed.
+
+use crate::{
+    blockchain::{
+        block::Block,
+        commit::{BlockVote, VoteKind},
+        consensus::ConsensusParams,
+        init::Blockchain,
+        mempool::Mempool,
+    },
+    net::{chat::ConnectionPool, transport::Transport},
+    node::Node,
+    p2p::bft::{Bft, BftTimeouts},
+    types::{
+        blockchain::{
+            ActionType, Admission, AgentRegistration, AgentRole, PayloadData, Transaction,
+            TransactionMessage,
+        },
+        error::ErrorTypes,
+    },
+    utils::{
+        clock::ManualClock,
+        crypto::{keypair_from_seed, sign_admission, sign_transaction, sign_vote, KeyPair},
+        message::{Envelope, MessageType},
+    },
+};
+use chrono::{DateTime, Duration, Utc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+// SplitMix64. Small enough to own, so the numbers a seed stands for never change with a
+// dependency update.
+#[derive(Clone, Debug)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> SimRng {
+        SimRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in `low..=high`
+    pub fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low + 1)
+    }
+
+    pub fn chance(&mut self, per_mille: u32) -> bool {
+        self.next_u64() % 1000 < per_mille as u64
+    }
+
+    pub fn seed_bytes(&mut self) -> [u8; 32] {
+        let mut seed = [0u8; 32];
+        for chunk in seed.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_be_bytes());
+        }
+        seed
+    }
+}
+
+// An agent that signs its own transactions, each with the next nonce
+pub struct SimAgent {
+    pub id: String,
+
+    pub role: AgentRole,
+
+    pub keypair: KeyPair,
+
+    pub nonce: u64,
+}
+
+impl SimAgent {
+    pub fn new(id: &str, role: AgentRole, keypair: KeyPair) -> SimAgent {
+        SimAgent {
+            id: id.to_string(),
+            role,
+            keypair,
+            nonce: 0,
+        }
+    }
+
+    // An agent whose key is derived from its id, the same in every run
+    pub fn from_id(id: &str, role: AgentRole) -> SimAgent {
+        let mut seed = [0u8; 32];
+        let len = id.len().min(seed.len());
+        seed[..len].copy_from_slice(&id.as_bytes()[..len]);
+        SimAgent::new(id, role, keypair_from_seed(seed))
+    }
+
+    pub fn sign(&mut self, action_type: ActionType, reasoning_hash: &str) -> TransactionMessage {
+        let agent_registration =
+            (action_type == ActionType::RegisterAgent).then(|| AgentRegistration {
+                role: self.role.clone(),
+                admitted_by: None,
+            });
+        self.sign_payload(action_type, reasoning_hash, agent_registration)
+    }
+
+    // Precommit of this agent, as a validator, for `block` in `round`
+    pub fn precommit(&self, block: &Block, round: u32) -> BlockVote {
+        let mut vote = BlockVote {
+            kind: VoteKind::Precommit,
+            height: block.index,
+            round,
+            block_hash: block.hash.clone(),
+            validator: self.id.clone(),
+            signature: String::new(),
+        };
+        sign_vote(&mut vote, &self.keypair).unwrap();
+        vote
+    }
+
+    // Registration of this agent carrying the admission of a validator
+    pub fn sign_admitted(&mut self, admission: Admission) -> TransactionMessage {
+        let agent_registration = AgentRegistration {
+            role: self.role.clone(),
+            admitted_by: Some(admission),
+        };
+        self.sign_payload(ActionType::RegisterAgent, "join", Some(agent_registration))
+    }
+
+    // Admission of `agent` signed by this agent, which has to be a registered validator for it
+    // to count
+    pub fn admit(&self, agent: &SimAgent) -> Admission {
+        sign_admission(
+            &agent.id,
+            &agent.keypair.public_key,
+            &agent.role,
+            &self.id,
+            &self.keypair,
+        )
+        .unwrap()
+    }
+
+    fn sign_payload(
+        &mut self,
+        action_type: ActionType,
+        reasoning_hash: &str,
+        agent_registration: Option<AgentRegistration>,
+    ) -> TransactionMessage {
+        let mut payload = Transaction {
+            agent_id: self.id.clone(),
+            nonce: self.nonce,
+            public_key: String::new(),
+            signature: String::new(),
+            reasoning_hash: reasoning_hash.to_string(),
+            action_type: action_type.clone(),
+            payload: PayloadData {
+                model_modification: None,
+                model_parameters: None,
+                evaluation_result: None,
+                agent_registration,
+                voting_deadline: None,
+                replaces_vote: None,
+                description: format!("{:?} {}", action_type, reasoning_hash),
+            },
+        };
+        self.nonce += 1;
+
+        sign_transaction(&mut payload, &self.keypair).unwrap();
+        TransactionMessage {
+            name: "transaction".to_string(),
+            payload,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub from_ms: u64,
+
+    pub until_ms: u64,
+
+    // while the partition lasts these nodes only reach each other
+    pub isolated: Vec<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub seed: u64,
+
+    pub nodes: usize,
+
+    pub consensus: ConsensusParams,
+
+    // every message takes between these two, drawn per message
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+
+    pub drop_per_mille: u32,
+
+    pub partitions: Vec<Partition>,
+
+    pub ping_interval_ms: u64,
+
+    pub bft: BftTimeouts,
+
+    // how often each node runs the timeouts of the commit protocol
+    pub tick_ms: u64,
+
+    // fault free time after the last submission and partition, for the nodes to converge
+    pub settle_ms: u64,
+}
+
+impl SimConfig {
+    pub fn new(seed: u64, nodes: usize) -> SimConfig {
+        SimConfig {
+            seed,
+            nodes,
+            consensus: ConsensusParams::default(),
+            min_delay_ms: 1,
+            max_delay_ms: 50,
+            drop_per_mille: 0,
+            partitions: Vec::new(),
+            ping_interval_ms: 500,
+            bft: BftTimeouts {
+                propose_ms: 600,
+                prevote_ms: 300,
+                precommit_ms: 300,
+                increase_ms: 100,
+                rebroadcast_ms: 200,
+            },
+            tick_ms: 50,
+            settle_ms: 5_000,
+        }
+    }
+}
+
+enum Event {
+    Deliver {
+        from: usize,
+        to: usize,
+        envelope: Box<Envelope>,
+    },
+
+    Submit {
+        node: usize,
+        agent: usize,
+        action_type: ActionType,
+        reasoning_hash: String,
+    },
+
+    Ping {
+        node: usize,
+    },
+
+    Tick {
+        node: usize,
+    },
+}
+
+// Holds what a simulated node broadcasts until the simulation routes it
+#[derive(Default)]
+struct Outbox(Mutex<Vec<Envelope>>);
+
+impl Transport for Outbox {
+    fn broadcast(&self, envelope: Envelope) {
+        self.0.lock().unwrap().push(envelope);
+    }
+}
+
+pub struct Simulation {
+    pub config: SimConfig,
+
+    pub nodes: Vec<Node>,
+
+    pub agents: Vec<SimAgent>,
+
+    // one line per event, the same seed always gives the same trace
+    pub trace: Vec<String>,
+
+    // submissions a node turned down, such as a vote sent before the node knew its proposal
+    pub rejected: Vec<(Transaction, ErrorTypes)>,
+
+    outboxes: Vec<Arc<Outbox>>,
+
+    clock: Arc<ManualClock>,
+
+    start: DateTime<Utc>,
+
+    rng: SimRng,
+
+    // keyed by delivery time, then by scheduling order
+    queue: BTreeMap<(u64, u64), Event>,
+
+    next_seq: u64,
+
+    now_ms: u64,
+
+    faults_until_ms: u64,
+}
+
+impl Simulation {
+    // `config.nodes` nodes sharing a genesis block that registers `agents`. Node i votes on
+    // blocks as the i-th validator among them, nodes beyond the last validator only follow.
+    pub async fn new(config: SimConfig, mut agents: Vec<SimAgent>) -> Simulation {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let genesis: Vec<Transaction> = agents
+            .iter_mut()
+            .map(|agent| agent.sign(ActionType::RegisterAgent, "genesis").payload)
+            .collect();
+
+        let mut keys = agents
+            .iter()
+            .filter(|agent| agent.role == AgentRole::Validator)
+            .map(|agent| agent.keypair.clone());
+
+        let mut nodes = Vec::new();
+        let mut outboxes = Vec::new();
+        for index in 0..config.nodes {
+            let outbox = Arc::new(Outbox::default());
+            nodes.push(
+                Node::with_parts(
+                    Blockchain::init(genesis.clone(), config.consensus),
+                    Mempool::default(),
+                    format!("sim-{}", index),
+                    Bft::new(keys.next(), config.bft),
+                    Arc::new(tokio::sync::Mutex::new(ConnectionPool::init())),
+                    clock.clone(),
+                    outbox.clone(),
+                )
+                .await,
+            );
+            outboxes.push(outbox);
+        }
+
+        let faults_until_ms = config
+            .partitions
+            .iter()
+            .map(|partition| partition.until_ms)
+            .max()
+            .unwrap_or(0);
+        let mut sim = Simulation {
+            rng: SimRng::new(config.seed),
+            config,
+            nodes,
+            agents,
+            trace: Vec::new(),
+            rejected: Vec::new(),
+            outboxes,
+            clock,
+            start,
+            queue: BTreeMap::new(),
+            next_seq: 0,
+            now_ms: 0,
+            faults_until_ms,
+        };
+
+        // every node pings and ticks on its own schedule
+        for node in 0..sim.nodes.len() {
+            let at = sim.rng.range(1, sim.config.ping_interval_ms);
+            sim.schedule(at, Event::Ping { node });
+            let at = sim.rng.range(1, sim.config.tick_ms);
+            sim.schedule(at, Event::Tick { node });
+        }
+        sim
+    }
+
+    // Has `agent` send a transaction to `node` at `at_ms`
+    pub fn submit(
+        &mut self,
+        at_ms: u64,
+        node: usize,
+        agent: &str,
+        action_type: ActionType,
+        reasoning_hash: &str,
+    ) {
+        let agent = self
+            .agents
+            .iter()
+            .position(|candidate| candidate.id == agent)
+            .unwrap();
+        self.faults_until_ms = self.faults_until_ms.max(at_ms);
+        self.schedule(
+            at_ms,
+            Event::Submit {
+                node,
+                agent,
+                action_type,
+                reasoning_hash: reasoning_hash.to_string(),
+            },
+        );
+    }
+
+    // Runs until every submission is handled and the network had `settle_ms` without faults
+    pub async fn run(&mut self) {
+        self.run_until(self.faults_until_ms + self.config.settle_ms)
+            .await;
+    }
+
+    // Handles every event due up to `end_ms`, later ones stay queued
+    pub async fn run_until(&mut self, end_ms: u64) {
+        while let Some(entry) = self.queue.first_entry() {
+            if entry.key().0 > end_ms {
+                break;
+            }
+            let ((at, _), event) = entry.remove_entry();
+            self.now_ms = at;
+            self.clock
+                .advance_to(self.start + Duration::milliseconds(at as i64));
+            self.handle(event).await;
+        }
+    }
+
+    fn schedule(&mut self, at_ms: u64, event: Event) {
+        self.queue.insert((at_ms, self.next_seq), event);
+        self.next_seq += 1;
+    }
+
+    async fn handle(&mut self, event: Event) {
+        match event {
+            Event::Deliver { from, to, envelope } => {
+                self.log(format!(
+                    "deliver {}->{} {}",
+                    from,
+                    to,
+                    describe(&envelope.message)
+                ));
+                let node = self.nodes[to].clone();
+                let p2p = node.p2p.lock().await;
+                for reply in p2p.respond(*envelope).await {
+                    self.send(to, from, p2p.envelope(reply));
+                }
+                drop(p2p);
+                self.flush(to);
+            }
+            Event::Submit {
+                node,
+                agent,
+                action_type,
+                reasoning_hash,
+            } => {
+                let tx_msg = self.agents[agent].sign(action_type.clone(), &reasoning_hash);
+                // as the HTTP API does: validate, then relay to the peers
+                let result = {
+                    let p2p = self.nodes[node].p2p.lock().await;
+                    let result = p2p.handle_transaction(tx_msg.clone()).await;
+                    if result.is_ok() {
+                        p2p.transport
+                            .broadcast(p2p.envelope(MessageType::Transaction(tx_msg.clone())));
+                    }
+                    result
+                };
+                self.log(format!(
+                    "submit {} {:?} {} to {}: {}",
+                    self.agents[agent].id,
+                    action_type,
+                    reasoning_hash,
+                    node,
+                    result.as_ref().map_or_else(|e| e.code(), |_| "ok")
+                ));
+
+                if let Err(err) = result {
+                    self.rejected.push((tx_msg.payload, err));
+                }
+                self.flush(node);
+            }
+            Event::Ping { node } => {
+                self.nodes[node].ping_peers().await;
+                self.flush(node);
+                self.schedule(
+                    self.now_ms + self.config.ping_interval_ms,
+                    Event::Ping { node },
+                );
+            }
+            // not traced, what a tick does shows in the messages it sends
+            Event::Tick { node } => {
+                self.nodes[node].p2p.lock().await.tick().await;
+                self.flush(node);
+                self.schedule(self.now_ms + self.config.tick_ms, Event::Tick { node });
+            }
+        }
+    }
+
+    // Sends whatever `node` broadcast to every other node
+    fn flush(&mut self, node: usize) {
+        let envelopes = std::mem::take(&mut *self.outboxes[node].0.lock().unwrap());
+        for envelope in envelopes {
+            for to in (0..self.nodes.len()).filter(|to| *to != node) {
+                self.send(node, to, envelope.clone());
+            }
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, envelope: Envelope) {
+        let faulty = self.now_ms < self.faults_until_ms;
+        if faulty && self.partitioned(from, to) {
+            self.log(format!(
+                "cut {}->{} {}",
+                from,
+                to,
+                describe(&envelope.message)
+            ));
+            return;
+        }
+        if faulty && self.rng.chance(self.config.drop_per_mille) {
+            self.log(format!(
+                "drop {}->{} {}",
+                from,
+                to,
+                describe(&envelope.message)
+            ));
+            return;
+        }
+
+        let delay = self
+            .rng
+            .range(self.config.min_delay_ms, self.config.max_delay_ms);
+        self.schedule(
+            self.now_ms + delay,
+            Event::Deliver {
+                from,
+                to,
+                envelope: Box::new(envelope),
+            },
+        );
+    }
+
+    fn partitioned(&self, a: usize, b: usize) -> bool {
+        self.config.partitions.iter().any(|partition| {
+            (partition.from_ms..partition.until_ms).contains(&self.now_ms)
+                && partition.isolated.contains(&a) != partition.isolated.contains(&b)
+        })
+    }
+
+    fn log(&mut self, line: String) {
+        self.trace.push(format!("{:>8} {}", self.now_ms, line));
+    }
+
+    pub async fn heights(&self) -> Vec<u32> {
+        let mut heights = Vec::new();
+        for node in &self.nodes {
+            heights.push(node.blockchain.lock().await.height());
+        }
+        heights
+    }
+
+    // What has to hold on every node whatever the network did: the chain verifies and no
+    // proposal is finalized twice
+    pub async fn check(&self) -> Result<(), String> {
+        for (index, node) in self.nodes.iter().enumerate() {
+            let blockchain = node.blockchain.lock().await;
+
+            let report = blockchain.verify();
+            if let Some(failure) = report.failure {
+                return Err(format!(
+                    "chain of node {} does not verify: {:?}",
+                    index, failure
+                ));
+            }
+
+            let mut finalized = HashSet::new();
+            for tx in blockchain
+                .blocks
+                .iter()
+                .flat_map(|block| block.transactions.iter())
+                .filter(|tx| tx.action_type == ActionType::ProposeUpdate)
+            {
+                if !finalized.insert(&tx.reasoning_hash) {
+                    return Err(format!(
+                        "node {} finalized proposal {} twice",
+                        index, tx.reasoning_hash
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // What has to hold once the network settled: all nodes hold the same chain
+    pub async fn converged(&self) -> Result<(), String> {
+        let mut chains = Vec::new();
+        for node in &self.nodes {
+            chains.push(
+                node.blockchain
+                    .lock()
+                    .await
+                    .blocks
+                    .iter()
+                    .map(|block| block.hash.clone())
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        for (index, chain) in chains.iter().enumerate().skip(1) {
+            if let Some(height) = (0..chain.len().max(chains[0].len()))
+                .find(|height| chain.get(*height) != chains[0].get(*height))
+            {
+                return Err(format!(
+                    "node {} and node 0 differ from height {} on, heights {} and {}",
+                    index,
+                    height,
+                    chain.len() - 1,
+                    chains[0].len() - 1
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn describe(message: &MessageType) -> String {
+    match message {
+        MessageType::Handshake(_) => "Handshake".to_string(),
+        MessageType::Block(block) => format!(
+            "Block({} {})",
+            block.index,
+            block.hash.as_deref().unwrap_or_default()
+        ),
+        MessageType::Proposal(proposal) => format!(
+            "Proposal({} r{} {})",
+            proposal.block.index,
+            proposal.round,
+            proposal.block.hash.as_deref().unwrap_or_default()
+        ),
+        MessageType::Vote(vote) => format!(
+            "{:?}({} r{} {} {})",
+            vote.kind,
+            vote.height,
+            vote.round,
+            vote.validator,
+            vote.block_hash.as_deref().unwrap_or("nil")
+        ),
+        MessageType::GetBlocks { from, to } => format!("GetBlocks({}..={})", from, to),
+        MessageType::Blocks { blocks, height } => {
+            format!("Blocks({} up to {})", blocks.len(), height)
+        }
+        MessageType::Peers(peers) => format!("Peers({})", peers.len()),
+        MessageType::Ping(ping) => format!("Ping({})", ping.block_height),
+        MessageType::Transaction(tx_msg) => format!("Transaction({})", tx_msg.payload.agent_id),
+        MessageType::Error(err) => format!("Error({})", err.code()),
+    }
+}
@@ -1,14 +1,98 @@
-use clap::Parser;
+use crate::blockchain::consensus::Fraction;
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
 
 #[derive(Clone, Debug, Parser)]
 #[command(name = "no_cap", version = "0.1.0", about = "What, you talkin' to me?")]
 pub struct Args {
-    #[arg(short, long, default_value = "Config.toml")]
-    pub config: String,
+    /// Config file, Config.toml in the working directory is used if it exists
+    #[arg(short, long)]
+    pub config: Option<String>,
 
     #[arg(short, long, default_value = ".env")]
     pub dotenv: String,
 
-    #[arg(short, long, default_value = "log_config.yml")]
-    pub log_config: String,
+    // The flags below override the config file and the environment
+    #[arg(short, long)]
+    pub log_config: Option<String>,
+
+    #[arg(short, long)]
+    pub genesis: Option<String>,
+
+    #[arg(long)]
+    pub data_dir: Option<String>,
+
+    /// Address (ip:port) the HTTP API listens on
+    #[arg(long)]
+    pub http_listen: Option<SocketAddr>,
+
+    /// Port of the HTTP API, keeping the address from the config
+    #[arg(long, conflicts_with = "http_listen")]
+    pub http_port: Option<u16>,
+
+    /// Address (ip:port) the P2P listener binds
+    #[arg(long)]
+    pub p2p_listen: Option<SocketAddr>,
+
+    /// Port of the P2P listener, keeping the address from the config
+    #[arg(long, conflicts_with = "p2p_listen")]
+    pub p2p_port: Option<u16>,
+
+    /// P2P address (host:port) other nodes reach this node at, skips address discovery
+    #[arg(long)]
+    pub advertise: Option<String>,
+
+    /// P2P address (host:port) of a node to connect to on startup, may be repeated. Replaces
+    /// the peers from the config.
+    #[arg(long = "peer")]
+    pub peers: Vec<String>,
+
+    /// Keypair file of the node, as produced by `keygen`
+    #[arg(long)]
+    pub key: Option<String>,
+
+    /// Share of the electorate that has to vote on a proposal, e.g. 2/3. Only used when
+    /// writing a new genesis block.
+    #[arg(long)]
+    pub quorum: Option<Fraction>,
+
+    /// Share of the votes cast needed to accept a proposal
+    #[arg(long)]
+    pub accept_threshold: Option<Fraction>,
+
+    /// Share of the votes cast needed to reject a proposal
+    #[arg(long)]
+    pub reject_threshold: Option<Fraction>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// Generate an ed25519 keypair for an agent and print it as JSON
+    Keygen,
+
+    /// Sign a transaction message with an agent keypair and print the signed message
+    Sign {
+        /// Path to a keypair file produced by `keygen`
+        #[arg(short, long)]
+        key: String,
+
+        /// Path to the unsigned transaction message
+        input: String,
+
+        /// Nonce to sign the transaction with, replacing the one in the message
+        #[arg(short, long)]
+        nonce: Option<u64>,
+    },
+
+    /// Check the chain stored in the data directory block by block and print a report. The
+    /// store is only read, unless repairing is asked for.
+    Verify {
+        /// Cut off a record an interrupted write left at the end of the store, as node startup
+        /// does, instead of reporting it
+        #[arg(long)]
+        repair: bool,
+    },
 }
@@ -1,6 +1,4 @@
-use std::time::SystemTime;
-
-use crate::{blockchain::block::Block, net::chat::Client};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -45,6 +43,67 @@ pub struct EvaluationResult {
     pub evaluation: Option<EvaluationVote>,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum AgentRole {
+    // submits model updates for the network to evaluate
+    Proposer,
+
+    // evaluates and votes on other agents' proposals
+    Evaluator,
+
+    // everything an evaluator does, plus finalizing blocks
+    Validator,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ProposalStatus {
+    Proposed,
+
+    UnderEvaluation,
+
+    Voting,
+
+    Accepted,
+
+    Rejected,
+
+    Expired,
+
+    // the accepted proposal has been included in a block
+    Finalized,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Deadline {
+    // voting closes once the chain reaches this height
+    Height(u32),
+
+    Time(DateTime<Utc>),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AgentRegistration {
+    // the registered public key is the one the registration itself is signed with
+    pub role: AgentRole,
+
+    // needed by evaluators and validators joining after genesis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admitted_by: Option<Admission>,
+}
+
+// A registered validator letting an agent join with a role whose votes or blocks count
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Admission {
+    pub validator: String,
+
+    // hex encoded ed25519 signature over the agent's id, public key and role, with the
+    // validator's registered key
+    pub signature: String,
+}
+
+// Optional fields added after the first release, here and in the registration it carries, are
+// left out of the JSON when unset, so the signed bytes of transactions that do not use them stay
+// the same
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PayloadData {
     pub model_modification: Option<ModelModification>,
@@ -53,6 +112,18 @@ pub struct PayloadData {
 
     pub evaluation_result: Option<EvaluationResult>,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_registration: Option<AgentRegistration>,
+
+    // only read on proposals, when left out the node's default voting period applies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voting_deadline: Option<Deadline>,
+
+    // only read on votes: id of the agent's active vote on the same proposal that this vote
+    // replaces. A second vote without it is rejected, the replaced vote stays in the record.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaces_vote: Option<String>,
+
     pub description: String,
 }
 
@@ -70,12 +141,24 @@ pub enum ActionType {
     FlagMalicious,
 
     FinalizeBlock,
+
+    RegisterAgent,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Transaction {
     pub agent_id: String,
 
+    // must be higher than every nonce the agent used before, in the chain or the mempool, so a
+    // transaction can never be replayed
+    #[serde(default)]
+    pub nonce: u64,
+
+    // hex encoded ed25519 public key the signature is checked against, filled in when signing
+    #[serde(default)]
+    pub public_key: String,
+
+    // hex encoded ed25519 signature over the transaction with this field left out
     pub signature: String,
 
     // if the change is questioned later on, it should be shown and will be verified if the
@@ -96,7 +179,14 @@ pub struct Ping {
     pub is_miner: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Handshake {
+    pub nickname: String,
+
+    pub block_height: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TransactionMessage {
     pub name: String,
 
@@ -105,7 +195,7 @@ pub struct TransactionMessage {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PeerAddr {
-    ip: String,
+    pub ip: String,
 
-    port: u32,
+    pub port: u32,
 }
@@ -0,0 +1,442 @@
+use super::{args::Args, error::ErrorTypes};
+use crate::{
+    blockchain::{
+        consensus::{ConsensusParams, Fraction},
+        mempool::MempoolLimits,
+    },
+    p2p::bft::BftTimeouts,
+    utils::crypto::{load_keypair, KeyPair},
+};
+use serde::Deserialize;
+use std::{net::SocketAddr, path::Path, str::FromStr};
+
+const DEFAULT_CONFIG_PATH: &str = "Config.toml";
+
+// Prefix of the environment variables that override the config file, e.g. NO_CAP_DATA_DIR
+const ENV_PREFIX: &str = "NO_CAP_";
+
+// Settings of a node. Every value has a default, the config file overrides the defaults,
+// environment variables override the file and command line flags override everything.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub application: ApplicationConfig,
+
+    #[serde(default)]
+    pub node: NodeConfig,
+
+    #[serde(default)]
+    pub http: HttpConfig,
+
+    #[serde(default)]
+    pub p2p: P2PConfig,
+
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+
+    #[serde(default)]
+    pub consensus: ConsensusParams,
+
+    #[serde(default)]
+    pub mempool: MempoolLimits,
+
+    #[serde(default)]
+    pub bft: BftTimeouts,
+}
+
+// Describes the build the config file was written for, not read by the node
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApplicationConfig {
+    pub name: String,
+
+    pub version: String,
+}
+
+impl Default for ApplicationConfig {
+    fn default() -> Self {
+        ApplicationConfig {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub data_dir: String,
+
+    pub genesis: String,
+
+    pub log_config: String,
+
+    // keypair file of the node itself, as produced by `keygen`
+    pub key: Option<String>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            data_dir: "data".to_string(),
+            genesis: "genesis.json".to_string(),
+            log_config: "log_config.yml".to_string(),
+            key: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub listen: SocketAddr,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct P2PConfig {
+    pub listen: SocketAddr,
+
+    // host:port other nodes reach us at, discovered at startup if unset
+    pub advertise: Option<String>,
+
+    // host:port of nodes to connect to on startup
+    pub peers: Vec<String>,
+}
+
+impl Default for P2PConfig {
+    fn default() -> Self {
+        P2PConfig {
+            listen: SocketAddr::from(([0, 0, 0, 0], 2373)),
+            advertise: None,
+            peers: Vec::new(),
+        }
+    }
+}
+
+// How the node finds out its own IP when `p2p.advertise` is not set
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscoveryBackend {
+    // skip discovery and use the fallback address
+    None,
+
+    // ask an ipinfo.io style service that answers with {"ip": ...}
+    Http,
+
+    // ask the address responder of another node over UDP
+    Stun,
+
+    // use the address of a local network interface
+    Interfaces,
+}
+
+impl FromStr for DiscoveryBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(DiscoveryBackend::None),
+            "http" => Ok(DiscoveryBackend::Http),
+            "stun" => Ok(DiscoveryBackend::Stun),
+            "interfaces" => Ok(DiscoveryBackend::Interfaces),
+            _ => Err("expected one of none, http, stun, interfaces".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    pub backend: DiscoveryBackend,
+
+    // used by the `http` backend
+    pub url: String,
+
+    // host:port of a node running the address responder, used by the `stun` backend
+    pub responder: Option<String>,
+
+    pub timeout_secs: u64,
+
+    // answer address requests from other nodes on the UDP side of the P2P port
+    pub respond: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            backend: DiscoveryBackend::Http,
+            url: "https://ipinfo.io".to_string(),
+            responder: None,
+            timeout_secs: 5,
+            respond: false,
+        }
+    }
+}
+
+impl Config {
+    // Builds the config the node runs with from the file, the environment and the flags, and
+    // checks it
+    pub fn resolve(args: &Args) -> Result<Config, ErrorTypes> {
+        let mut config = match &args.config {
+            Some(path) => Config::load(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).is_file() => Config::load(DEFAULT_CONFIG_PATH)?,
+            None => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(format!("{}{}", ENV_PREFIX, name)).ok())?;
+        config.apply_args(args);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn load(path: &str) -> Result<Config, ErrorTypes> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            ErrorTypes::InvalidConfig(format!("Error while reading config file {}: {}", path, e))
+        })?;
+
+        toml::from_str(&content).map_err(|e| {
+            ErrorTypes::InvalidConfig(format!("Error while parsing config file {}: {}", path, e))
+        })
+    }
+
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ErrorTypes> {
+        if let Some(value) = var("DATA_DIR") {
+            self.node.data_dir = value;
+        }
+        if let Some(value) = var("GENESIS") {
+            self.node.genesis = value;
+        }
+        if let Some(value) = var("LOG_CONFIG") {
+            self.node.log_config = value;
+        }
+        if let Some(value) = var("NODE_KEY") {
+            self.node.key = Some(value);
+        }
+        if let Some(value) = var("HTTP_LISTEN") {
+            self.http.listen = parse_env("HTTP_LISTEN", &value)?;
+        }
+        if let Some(value) = var("P2P_LISTEN") {
+            self.p2p.listen = parse_env("P2P_LISTEN", &value)?;
+        }
+        if let Some(value) = var("ADVERTISE") {
+            self.p2p.advertise = Some(value);
+        }
+        // comma separated
+        if let Some(value) = var("PEERS") {
+            self.p2p.peers = value
+                .split(',')
+                .map(str::trim)
+                .filter(|peer| !peer.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(value) = var("DISCOVERY") {
+            self.discovery.backend = parse_env("DISCOVERY", &value)?;
+        }
+        if let Some(value) = var("QUORUM") {
+            self.consensus.quorum = parse_fraction("QUORUM", value)?;
+        }
+        if let Some(value) = var("ACCEPT_THRESHOLD") {
+            self.consensus.accept_threshold = parse_fraction("ACCEPT_THRESHOLD", value)?;
+        }
+        if let Some(value) = var("REJECT_THRESHOLD") {
+            self.consensus.reject_threshold = parse_fraction("REJECT_THRESHOLD", value)?;
+        }
+        if let Some(value) = var("MEMPOOL_MAX_TRANSACTIONS") {
+            self.mempool.max_transactions = parse_env("MEMPOOL_MAX_TRANSACTIONS", &value)?;
+        }
+        if let Some(value) = var("MEMPOOL_MAX_AGE_SECS") {
+            self.mempool.max_age_secs = parse_env("MEMPOOL_MAX_AGE_SECS", &value)?;
+        }
+        if let Some(value) = var("BFT_PROPOSE_MS") {
+            self.bft.propose_ms = parse_env("BFT_PROPOSE_MS", &value)?;
+        }
+        if let Some(value) = var("BFT_PREVOTE_MS") {
+            self.bft.prevote_ms = parse_env("BFT_PREVOTE_MS", &value)?;
+        }
+        if let Some(value) = var("BFT_PRECOMMIT_MS") {
+            self.bft.precommit_ms = parse_env("BFT_PRECOMMIT_MS", &value)?;
+        }
+        if let Some(value) = var("BFT_INCREASE_MS") {
+            self.bft.increase_ms = parse_env("BFT_INCREASE_MS", &value)?;
+        }
+        if let Some(value) = var("BFT_REBROADCAST_MS") {
+            self.bft.rebroadcast_ms = parse_env("BFT_REBROADCAST_MS", &value)?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(data_dir) = &args.data_dir {
+            self.node.data_dir = data_dir.clone();
+        }
+        if let Some(genesis) = &args.genesis {
+            self.node.genesis = genesis.clone();
+        }
+        if let Some(log_config) = &args.log_config {
+            self.node.log_config = log_config.clone();
+        }
+        if let Some(key) = &args.key {
+            self.node.key = Some(key.clone());
+        }
+        if let Some(listen) = args.http_listen {
+            self.http.listen = listen;
+        }
+        if let Some(port) = args.http_port {
+            self.http.listen.set_port(port);
+        }
+        if let Some(listen) = args.p2p_listen {
+            self.p2p.listen = listen;
+        }
+        if let Some(port) = args.p2p_port {
+            self.p2p.listen.set_port(port);
+        }
+        if let Some(advertise) = &args.advertise {
+            self.p2p.advertise = Some(advertise.clone());
+        }
+        if !args.peers.is_empty() {
+            self.p2p.peers = args.peers.clone();
+        }
+        if let Some(quorum) = args.quorum {
+            self.consensus.quorum = quorum;
+        }
+        if let Some(threshold) = args.accept_threshold {
+            self.consensus.accept_threshold = threshold;
+        }
+        if let Some(threshold) = args.reject_threshold {
+            self.consensus.reject_threshold = threshold;
+        }
+    }
+
+    // Reports every problem at once rather than the first one
+    pub fn validate(&self) -> Result<(), ErrorTypes> {
+        let mut problems = Vec::new();
+
+        if self.node.data_dir.trim().is_empty() {
+            problems.push("node.data_dir must not be empty".to_string());
+        }
+        if !Path::new(&self.node.log_config).is_file() {
+            problems.push(format!(
+                "node.log_config: no log config at {}",
+                self.node.log_config
+            ));
+        }
+        if let Err(e) = self.node_key() {
+            problems.push(format!("node.key: {}", e.reason()));
+        }
+
+        if listeners_overlap(self.http.listen, self.p2p.listen) {
+            problems.push(format!(
+                "http.listen and p2p.listen both use {}",
+                self.p2p.listen
+            ));
+        }
+        for peer in &self.p2p.peers {
+            if !is_host_port(peer) {
+                problems.push(format!("p2p.peers: {:?} is not a host:port address", peer));
+            }
+        }
+        if let Some(advertise) = &self.p2p.advertise
+            && !is_host_port(advertise)
+        {
+            problems.push(format!(
+                "p2p.advertise: {:?} is not a host:port address",
+                advertise
+            ));
+        }
+
+        match (&self.discovery.backend, &self.discovery.responder) {
+            (DiscoveryBackend::Stun, None) => {
+                problems.push("discovery.responder is required by the stun backend".to_string())
+            }
+            (_, Some(responder)) if !is_host_port(responder) => problems.push(format!(
+                "discovery.responder: {:?} is not a host:port address",
+                responder
+            )),
+            _ => {}
+        }
+        if self.discovery.backend == DiscoveryBackend::Http && self.discovery.url.is_empty() {
+            problems.push("discovery.url is required by the http backend".to_string());
+        }
+        if self.discovery.timeout_secs == 0 {
+            problems.push("discovery.timeout_secs must be at least 1".to_string());
+        }
+
+        // otherwise a proposal could reach both thresholds with the same votes
+        let accept = self.consensus.accept_threshold;
+        let reject = self.consensus.reject_threshold;
+        if accept.numerator as u64 * reject.denominator as u64
+            + reject.numerator as u64 * accept.denominator as u64
+            <= accept.denominator as u64 * reject.denominator as u64
+        {
+            problems.push(format!(
+                "consensus: accept_threshold {} and reject_threshold {} must add up to more than 1",
+                accept, reject
+            ));
+        }
+
+        if self.mempool.max_transactions == 0 {
+            problems.push("mempool.max_transactions must be at least 1".to_string());
+        }
+        if self.mempool.max_age_secs == 0 {
+            problems.push("mempool.max_age_secs must be at least 1".to_string());
+        }
+
+        for (name, value) in [
+            ("propose_ms", self.bft.propose_ms),
+            ("prevote_ms", self.bft.prevote_ms),
+            ("precommit_ms", self.bft.precommit_ms),
+            ("rebroadcast_ms", self.bft.rebroadcast_ms),
+        ] {
+            if value == 0 {
+                problems.push(format!("bft.{} must be at least 1", name));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorTypes::InvalidConfig(problems.join("\n")))
+        }
+    }
+
+    pub fn node_key(&self) -> Result<Option<KeyPair>, ErrorTypes> {
+        self.node.key.as_deref().map(load_keypair).transpose()
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ErrorTypes>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e| {
+        ErrorTypes::InvalidConfig(format!("{}{}={:?}: {}", ENV_PREFIX, name, value, e))
+    })
+}
+
+fn parse_fraction(name: &str, value: String) -> Result<Fraction, ErrorTypes> {
+    Fraction::try_from(value)
+        .map_err(|e| ErrorTypes::InvalidConfig(format!("{}{}: {}", ENV_PREFIX, name, e)))
+}
+
+fn is_host_port(address: &str) -> bool {
+    address
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+}
+
+// Whether binding one address would keep the other from binding
+fn listeners_overlap(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() == b.port() && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
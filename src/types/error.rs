@@ -3,4 +3,122 @@ use serde::{Deserialize, Serialize};
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum ErrorTypes {
     TransactionSerializeError(String),
+
+    MalformedTransaction(String),
+
+    MalformedMessage(String),
+
+    UnsupportedVersion(String),
+
+    UnsupportedMessage(String),
+
+    FrameTooLarge(String),
+
+    InvalidKey(String),
+
+    InvalidSignature(String),
+
+    InvalidProposal(String),
+
+    InvalidRegistration(String),
+
+    UnknownAgent(String),
+
+    Unauthorized(String),
+
+    StorageError(String),
+
+    UnknownProposal(String),
+
+    InvalidTransition(String),
+
+    InvalidBlock(String),
+
+    InvalidVote(String),
+
+    UnsupportedAction(String),
+
+    InvalidNonce(String),
+
+    InvalidConfig(String),
+
+    DiscoveryError(String),
+
+    DuplicateTransaction(String),
+
+    MempoolFull(String),
+}
+
+// What API clients get back for a rejected request: a stable, machine readable code and a
+// human readable reason
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub code: String,
+
+    pub reason: String,
+}
+
+impl ErrorTypes {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorTypes::TransactionSerializeError(_) => "transaction_serialize_error",
+            ErrorTypes::MalformedTransaction(_) => "malformed_transaction",
+            ErrorTypes::MalformedMessage(_) => "malformed_message",
+            ErrorTypes::UnsupportedVersion(_) => "unsupported_version",
+            ErrorTypes::UnsupportedMessage(_) => "unsupported_message",
+            ErrorTypes::FrameTooLarge(_) => "frame_too_large",
+            ErrorTypes::InvalidKey(_) => "invalid_key",
+            ErrorTypes::InvalidSignature(_) => "invalid_signature",
+            ErrorTypes::InvalidProposal(_) => "invalid_proposal",
+            ErrorTypes::InvalidRegistration(_) => "invalid_registration",
+            ErrorTypes::UnknownAgent(_) => "unknown_agent",
+            ErrorTypes::Unauthorized(_) => "unauthorized",
+            ErrorTypes::StorageError(_) => "storage_error",
+            ErrorTypes::UnknownProposal(_) => "unknown_proposal",
+            ErrorTypes::InvalidTransition(_) => "invalid_transition",
+            ErrorTypes::InvalidBlock(_) => "invalid_block",
+            ErrorTypes::InvalidVote(_) => "invalid_vote",
+            ErrorTypes::UnsupportedAction(_) => "unsupported_action",
+            ErrorTypes::InvalidNonce(_) => "invalid_nonce",
+            ErrorTypes::InvalidConfig(_) => "invalid_config",
+            ErrorTypes::DiscoveryError(_) => "discovery_error",
+            ErrorTypes::DuplicateTransaction(_) => "duplicate_transaction",
+            ErrorTypes::MempoolFull(_) => "mempool_full",
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            ErrorTypes::TransactionSerializeError(reason)
+            | ErrorTypes::MalformedTransaction(reason)
+            | ErrorTypes::MalformedMessage(reason)
+            | ErrorTypes::UnsupportedVersion(reason)
+            | ErrorTypes::UnsupportedMessage(reason)
+            | ErrorTypes::FrameTooLarge(reason)
+            | ErrorTypes::InvalidKey(reason)
+            | ErrorTypes::InvalidSignature(reason)
+            | ErrorTypes::InvalidProposal(reason)
+            | ErrorTypes::InvalidRegistration(reason)
+            | ErrorTypes::UnknownAgent(reason)
+            | ErrorTypes::Unauthorized(reason)
+            | ErrorTypes::StorageError(reason)
+            | ErrorTypes::UnknownProposal(reason)
+            | ErrorTypes::InvalidTransition(reason)
+            | ErrorTypes::InvalidBlock(reason)
+            | ErrorTypes::InvalidVote(reason)
+            | ErrorTypes::UnsupportedAction(reason)
+            | ErrorTypes::InvalidNonce(reason)
+            | ErrorTypes::InvalidConfig(reason)
+            | ErrorTypes::DiscoveryError(reason)
+            | ErrorTypes::DuplicateTransaction(reason)
+            | ErrorTypes::MempoolFull(reason) => reason,
+        }
+    }
+
+    pub fn report(&self) -> ErrorReport {
+        ErrorReport {
+            code: self.code().to_string(),
+            reason: self.reason().to_string(),
+        }
+    }
 }
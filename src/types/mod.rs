@@ -1,4 +1,4 @@
 pub mod args;
 pub mod blockchain;
-pub mod chat;
+pub mod config;
 pub mod error;
@@ -0,0 +1,44 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Mutex;
+
+// Where a node gets the time from: the system clock normally, a simulated one in tests
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// A clock that only moves when told to. Like a real clock, two readings are never equal: each
+// one is a microsecond after the previous, so blocks built at the same simulated instant still
+// get increasing timestamps.
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Utc>) -> ManualClock {
+        ManualClock {
+            now: Mutex::new(start),
+        }
+    }
+
+    // Moves the clock forward to `time`, a time in the past leaves it where it is
+    pub fn advance_to(&self, time: DateTime<Utc>) {
+        let mut now = self.now.lock().unwrap();
+        *now = (*now).max(time);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        let mut now = self.now.lock().unwrap();
+        *now += Duration::microseconds(1);
+        *now
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{
+    blockchain::{
+        block::Block,
+        commit::{BlockVote, VoteKind},
+    },
+    types::{
+        blockchain::{ActionType, Admission, AgentRole, PayloadData, Transaction},
+        error::ErrorTypes,
+    },
+    utils::hasher::block_header_bytes,
+};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::sign::{self, PublicKey, SecretKey, Signature};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeyPair {
+    pub public_key: String,
+
+    pub secret_key: String,
+}
+
+// Everything in a transaction except the signature, in a fixed field order, so the bytes that
+// are signed do not depend on how the submitter happened to lay out its JSON
+#[derive(Serialize)]
+struct UnsignedTransaction<'a> {
+    agent_id: &'a str,
+
+    nonce: u64,
+
+    public_key: &'a str,
+
+    reasoning_hash: &'a str,
+
+    action_type: &'a ActionType,
+
+    payload: &'a PayloadData,
+}
+
+// Everything in a vote except the signature, like `UnsignedTransaction`
+#[derive(Serialize)]
+struct UnsignedVote<'a> {
+    kind: VoteKind,
+
+    height: u32,
+
+    round: u32,
+
+    block_hash: Option<&'a str>,
+
+    validator: &'a str,
+}
+
+// What a validator signs to admit an agent, like `UnsignedTransaction`
+#[derive(Serialize)]
+struct UnsignedAdmission<'a> {
+    agent_id: &'a str,
+
+    public_key: &'a str,
+
+    role: &'a AgentRole,
+}
+
+pub fn generate_keypair() -> KeyPair {
+    let (public_key, secret_key) = sign::gen_keypair();
+
+    KeyPair {
+        public_key: hex::encode(public_key.as_ref()),
+        secret_key: hex::encode(secret_key.as_ref()),
+    }
+}
+
+// The same seed always gives the same keypair, for reproducible simulations
+pub fn keypair_from_seed(seed: [u8; 32]) -> KeyPair {
+    let (public_key, secret_key) = sign::keypair_from_seed(&sign::Seed(seed));
+
+    KeyPair {
+        public_key: hex::encode(public_key.as_ref()),
+        secret_key: hex::encode(secret_key.as_ref()),
+    }
+}
+
+pub fn transaction_signing_bytes(tx: &Transaction) -> Result<Vec<u8>, ErrorTypes> {
+    let unsigned = UnsignedTransaction {
+        agent_id: &tx.agent_id,
+        nonce: tx.nonce,
+        public_key: &tx.public_key,
+        reasoning_hash: &tx.reasoning_hash,
+        action_type: &tx.action_type,
+        payload: &tx.payload,
+    };
+
+    serde_json::to_vec(&unsigned).map_err(|e| {
+        ErrorTypes::TransactionSerializeError(format!(
+            "Error while serializing transaction for signing: {:?}",
+            e
+        ))
+    })
+}
+
+fn decode_public_key(public_key: &str) -> Result<PublicKey, ErrorTypes> {
+    hex::decode(public_key)
+        .ok()
+        .and_then(|bytes| PublicKey::from_slice(&bytes))
+        .ok_or_else(|| {
+            ErrorTypes::InvalidKey(format!("Invalid ed25519 public key: {:?}", public_key))
+        })
+}
+
+fn decode_secret_key(secret_key: &str) -> Result<SecretKey, ErrorTypes> {
+    hex::decode(secret_key)
+        .ok()
+        .and_then(|bytes| SecretKey::from_slice(&bytes))
+        .ok_or_else(|| ErrorTypes::InvalidKey("Invalid ed25519 secret key".to_string()))
+}
+
+// Reads a keypair file produced by `keygen`, checking that it holds a usable secret key
+pub fn load_keypair(path: &str) -> Result<KeyPair, ErrorTypes> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| ErrorTypes::InvalidKey(format!("Error while reading key {}: {}", path, e)))?;
+    let keypair: KeyPair = serde_json::from_str(&content)
+        .map_err(|e| ErrorTypes::InvalidKey(format!("Error while parsing key {}: {}", path, e)))?;
+    let secret_key = decode_secret_key(&keypair.secret_key)?;
+    if hex::encode(secret_key.public_key().as_ref()) != keypair.public_key {
+        return Err(ErrorTypes::InvalidKey(format!(
+            "Public key in {} does not belong to its secret key",
+            path
+        )));
+    }
+
+    Ok(keypair)
+}
+
+pub fn sign_transaction(tx: &mut Transaction, keypair: &KeyPair) -> Result<(), ErrorTypes> {
+    let secret_key = decode_secret_key(&keypair.secret_key)?;
+    tx.public_key = hex::encode(secret_key.public_key().as_ref());
+
+    let message = transaction_signing_bytes(tx)?;
+    let signature = sign::sign_detached(&message, &secret_key);
+    tx.signature = hex::encode(signature.to_bytes());
+
+    Ok(())
+}
+
+// Signs the header of a sealed block as its producer
+pub fn sign_block(block: &mut Block, keypair: &KeyPair) -> Result<(), ErrorTypes> {
+    let secret_key = decode_secret_key(&keypair.secret_key)?;
+    let signature = sign::sign_detached(&block_header_bytes(block), &secret_key);
+    block.signature = hex::encode(signature.to_bytes());
+
+    Ok(())
+}
+
+// Whether the hex encoded `signature` over `message` was made with the hex encoded `public_key`.
+// A signature that is not even well formed does not match either.
+fn verify_detached(signature: &str, message: &[u8], public_key: &str) -> Result<bool, ErrorTypes> {
+    let public_key = decode_public_key(public_key)?;
+
+    Ok(hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_bytes(&bytes).ok())
+        .is_some_and(|signature| sign::verify_detached(&signature, message, &public_key)))
+}
+
+pub fn verify_block_signature(block: &Block, public_key: &str) -> Result<(), ErrorTypes> {
+    if verify_detached(&block.signature, &block_header_bytes(block), public_key)? {
+        Ok(())
+    } else {
+        Err(ErrorTypes::InvalidSignature(format!(
+            "Signature does not match block {} from {}",
+            block.index, block.producer
+        )))
+    }
+}
+
+fn vote_signing_bytes(vote: &BlockVote) -> Result<Vec<u8>, ErrorTypes> {
+    let unsigned = UnsignedVote {
+        kind: vote.kind,
+        height: vote.height,
+        round: vote.round,
+        block_hash: vote.block_hash.as_deref(),
+        validator: &vote.validator,
+    };
+
+    serde_json::to_vec(&unsigned).map_err(|e| {
+        ErrorTypes::TransactionSerializeError(format!(
+            "Error while serializing vote for signing: {:?}",
+            e
+        ))
+    })
+}
+
+pub fn sign_vote(vote: &mut BlockVote, keypair: &KeyPair) -> Result<(), ErrorTypes> {
+    let secret_key = decode_secret_key(&keypair.secret_key)?;
+    let signature = sign::sign_detached(&vote_signing_bytes(vote)?, &secret_key);
+    vote.signature = hex::encode(signature.to_bytes());
+
+    Ok(())
+}
+
+pub fn verify_vote(vote: &BlockVote, public_key: &str) -> Result<(), ErrorTypes> {
+    if verify_detached(&vote.signature, &vote_signing_bytes(vote)?, public_key)? {
+        Ok(())
+    } else {
+        Err(ErrorTypes::InvalidSignature(format!(
+            "Signature does not match {:?} of {} on block {} in round {}",
+            vote.kind, vote.validator, vote.height, vote.round
+        )))
+    }
+}
+
+// Checks that the transaction was signed with the key it declares. That alone does not make it
+// the agent's: anyone can declare their own key, `AgentRegistry::authorize` checks that it is
+// the key the agent registered.
+pub fn verify_transaction(tx: &Transaction) -> Result<(), ErrorTypes> {
+    let message = transaction_signing_bytes(tx)?;
+    if verify_detached(&tx.signature, &message, &tx.public_key)? {
+        Ok(())
+    } else {
+        Err(ErrorTypes::InvalidSignature(format!(
+            "Signature does not match transaction from agent {}",
+            tx.agent_id
+        )))
+    }
+}
+
+fn admission_signing_bytes(
+    agent_id: &str,
+    public_key: &str,
+    role: &AgentRole,
+) -> Result<Vec<u8>, ErrorTypes> {
+    let unsigned = UnsignedAdmission {
+        agent_id,
+        public_key,
+        role,
+    };
+
+    serde_json::to_vec(&unsigned).map_err(|e| {
+        ErrorTypes::TransactionSerializeError(format!(
+            "Error while serializing admission for signing: {:?}",
+            e
+        ))
+    })
+}
+
+// Signs, as the registered validator `validator`, that agent `agent_id` may join with `role`
+// under `public_key`
+pub fn sign_admission(
+    agent_id: &str,
+    public_key: &str,
+    role: &AgentRole,
+    validator: &str,
+    keypair: &KeyPair,
+) -> Result<Admission, ErrorTypes> {
+    let secret_key = decode_secret_key(&keypair.secret_key)?;
+    let message = admission_signing_bytes(agent_id, public_key, role)?;
+    let signature = sign::sign_detached(&message, &secret_key);
+
+    Ok(Admission {
+        validator: validator.to_string(),
+        signature: hex::encode(signature.to_bytes()),
+    })
+}
+
+// Checks that `admission` lets the sender of the registration `tx` join with `role`, signed
+// with `public_key` of the admitting validator
+pub fn verify_admission(
+    tx: &Transaction,
+    role: &AgentRole,
+    admission: &Admission,
+    public_key: &str,
+) -> Result<(), ErrorTypes> {
+    let message = admission_signing_bytes(&tx.agent_id, &tx.public_key, role)?;
+    if verify_detached(&admission.signature, &message, public_key)? {
+        Ok(())
+    } else {
+        Err(ErrorTypes::InvalidSignature(format!(
+            "Admission of agent {} as {:?} is not signed by {}",
+            tx.agent_id, role, admission.validator
+        )))
+    }
+}
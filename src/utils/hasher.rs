@@ -1,25 +1,7 @@
-use crate::{
-    blockchain::block::Block,
-    types::{blockchain::Transaction, error::ErrorTypes},
-};
+use crate::{blockchain::block::Block, types::blockchain::Transaction};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-fn transaction_serialize(transactions: &Vec<Transaction>) -> Result<String, ErrorTypes> {
-    match serde_json::to_string(transactions) {
-        Ok(transation) => {
-            log::info!("Transaction successfully serialized!");
-            Ok(transation)
-        }
-        Err(e) => {
-            log::error!("Error while serializing transactions");
-            return Err(ErrorTypes::TransactionSerializeError(format!(
-                "Error while seriazling transactions: {:?}",
-                e
-            )));
-        }
-    }
-}
-
 fn hasher(input: String) -> String {
     let mut hasher = Sha256::new();
 
@@ -29,16 +11,188 @@ fn hasher(input: String) -> String {
     hex::encode(hex_digest)
 }
 
+// Version of the header encoding below, hashed along with it so a future layout can never
+// produce the same bytes as this one
+pub const BLOCK_HEADER_VERSION: u8 = 1;
+
+fn push_str(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+// Canonical encoding of the block header, the only input to the block hash:
+//
+//   version      u8
+//   index        u32 BE
+//   prev_hash    u32 BE length + UTF-8
+//   timestamp    i64 BE seconds since the Unix epoch + u32 BE nanoseconds
+//   merkle_root  u32 BE length + UTF-8 (empty if unset)
+//   producer     u32 BE length + UTF-8
+//   round        only if not 0: u8 2, then u32 BE
+//   consensus    only if set (genesis): u8 1, then quorum, accept and reject threshold, each as
+//                u32 BE numerator + u32 BE denominator
+//
+// The block's own hash is never part of it, so a block hashes the same before and after sealing.
+pub fn block_header_bytes(block: &Block) -> Vec<u8> {
+    let mut bytes = vec![BLOCK_HEADER_VERSION];
+    bytes.extend_from_slice(&block.index.to_be_bytes());
+    push_str(&mut bytes, &block.prev_hash);
+    bytes.extend_from_slice(&block.timestamp.timestamp().to_be_bytes());
+    bytes.extend_from_slice(&block.timestamp.timestamp_subsec_nanos().to_be_bytes());
+    push_str(&mut bytes, block.merkle_root.as_deref().unwrap_or(""));
+    push_str(&mut bytes, &block.producer);
+    if block.round != 0 {
+        bytes.push(2);
+        bytes.extend_from_slice(&block.round.to_be_bytes());
+    }
+    if let Some(consensus) = &block.consensus {
+        bytes.push(1);
+        for fraction in [
+            consensus.quorum,
+            consensus.accept_threshold,
+            consensus.reject_threshold,
+        ] {
+            bytes.extend_from_slice(&fraction.numerator.to_be_bytes());
+            bytes.extend_from_slice(&fraction.denominator.to_be_bytes());
+        }
+    }
+    bytes
+}
+
 pub fn block_hasher(block: &Block) -> String {
-    let transaction = transaction_serialize(&block.transactions).unwrap();
-    let input = format!(
-        "{:?}{:?}{:?}{:?}{:?}",
-        block.index, block.prev_hash, block.hash, transaction, block.timestamp
-    );
-    hasher(input)
+    hex::encode(Sha256::digest(block_header_bytes(block)))
+}
+
+// Id of a transaction: the SHA-256 of its JSON, also what the merkle tree leaves are built from
+pub fn transaction_hash(tx: &Transaction) -> String {
+    hasher(serde_json::to_string(tx).unwrap())
+}
+
+// Leaves and inner nodes are hashed with different prefixes so an inner node can never be passed
+// off as a leaf
+fn merkle_leaf(tx_hash: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update([0x00])
+        .chain_update(tx_hash)
+        .finalize()
+        .to_vec()
+}
+
+fn merkle_node(left: &[u8], right: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update([0x01])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .to_vec()
+}
+
+// Every level of the tree, leaves first and the root last. A node without a sibling is carried up
+// to the next level as is instead of being paired with a copy of itself.
+fn merkle_levels(transactions: &[Transaction]) -> Vec<Vec<Vec<u8>>> {
+    let mut levels = vec![transactions
+        .iter()
+        .map(|tx| merkle_leaf(&hex::decode(transaction_hash(tx)).unwrap()))
+        .collect::<Vec<_>>()];
+
+    while levels.last().unwrap().len() > 1 {
+        let next = levels
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => merkle_node(left, right),
+                [single] => single.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(next);
+    }
+
+    levels
+}
+
+// Root of the binary merkle tree over the transaction hashes, the hash of nothing if there are none
+pub fn merkle_root(transactions: &[Transaction]) -> String {
+    match merkle_levels(transactions)
+        .last()
+        .and_then(|level| level.first())
+    {
+        Some(root) => hex::encode(root),
+        None => hex::encode(Sha256::digest([])),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ProofSide {
+    Left,
+
+    Right,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProofStep {
+    pub hash: String,
+
+    // which side of the path the sibling sits on
+    pub side: ProofSide,
 }
 
-pub fn transactions_hasher(transactions: &Vec<Transaction>) -> String {
-    let transaction = transaction_serialize(&transactions).unwrap();
-    hasher(transaction)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MerkleProof {
+    pub tx_hash: String,
+
+    pub tx_index: usize,
+
+    // siblings from the leaf up to the root
+    pub path: Vec<ProofStep>,
+}
+
+// Proof that the transaction at `tx_index` is part of `block`
+pub fn merkle_proof(block: &Block, tx_index: usize) -> Option<MerkleProof> {
+    let tx = block.transactions.get(tx_index)?;
+    let levels = merkle_levels(&block.transactions);
+
+    let mut path = Vec::new();
+    let mut position = tx_index;
+    for level in &levels[..levels.len() - 1] {
+        let sibling = position ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            path.push(ProofStep {
+                hash: hex::encode(hash),
+                side: if sibling < position {
+                    ProofSide::Left
+                } else {
+                    ProofSide::Right
+                },
+            });
+        }
+        position /= 2;
+    }
+
+    Some(MerkleProof {
+        tx_hash: transaction_hash(tx),
+        tx_index,
+        path,
+    })
+}
+
+// Checks the proof without the block, only the transaction hash and the merkle root are needed
+pub fn verify_merkle_proof(proof: &MerkleProof, merkle_root: &str) -> bool {
+    let Ok(tx_hash) = hex::decode(&proof.tx_hash) else {
+        return false;
+    };
+
+    let mut node = merkle_leaf(&tx_hash);
+    for step in &proof.path {
+        let Ok(sibling) = hex::decode(&step.hash) else {
+            return false;
+        };
+        node = match step.side {
+            ProofSide::Left => merkle_node(&sibling, &node),
+            ProofSide::Right => merkle_node(&node, &sibling),
+        };
+    }
+
+    hex::encode(node) == merkle_root
 }
@@ -1,33 +1,100 @@
-use crate::blockchain::block::Block;
+use crate::{
+    blockchain::{
+        block::Block,
+        commit::{BlockProposal, BlockVote},
+    },
+    types::{
+        blockchain::{Handshake, PeerAddr, Ping, TransactionMessage},
+        error::ErrorTypes,
+    },
+};
 use serde::{Deserialize, Serialize};
 
+pub const PROTOCOL_VERSION: &str = "2.0.0";
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Meta {
-    version: String,
+    pub version: String,
 
-    address: String,
+    // address the sending node can be reached at
+    pub address: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct BlockMessage {
+#[serde(tag = "type", content = "payload")]
+pub enum MessageType {
+    // must be the first message on a connection, in both directions
+    Handshake(Handshake),
+
+    // a freshly committed block, gossiped to every peer
+    Block(Block),
+
+    // a block offered by a validator for the next height, see `p2p::bft`
+    Proposal(BlockProposal),
+
+    Vote(BlockVote),
+
+    // asks a peer for the blocks with a height in `from..=to`
+    GetBlocks { from: u32, to: u32 },
+
+    // reply to `GetBlocks`, possibly fewer blocks than asked for, `height` is the sender's tip
+    Blocks { blocks: Vec<Block>, height: u32 },
+
+    Peers(Vec<PeerAddr>),
+
+    Ping(Ping),
+
+    Transaction(TransactionMessage),
+
+    // reply to a message that could not be parsed, is not supported or was rejected
+    Error(ErrorTypes),
+}
+
+// Everything on the P2P port is one of these, serialized as JSON in a length-prefixed frame
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Envelope {
     pub meta: Meta,
 
-    pub payload: Block,
+    pub message: MessageType,
 }
 
-fn meta(external_ip: String, port: usize) -> Meta {
+// `address` is the advertised P2P address of the sending node
+pub fn meta(address: &str) -> Meta {
     Meta {
-        version: String::from("1.0.0"),
-        address: format!("{}:{}", external_ip, port),
+        version: String::from(PROTOCOL_VERSION),
+        address: address.to_string(),
     }
 }
 
-pub async fn create_block_message(ip: String, port: usize, block: Block) -> String {
-    let meta = meta(ip, port);
-    let message = BlockMessage {
-        meta,
-        payload: block,
-    };
+fn major_version(version: &str) -> Option<&str> {
+    version.split('.').next()
+}
+
+impl Envelope {
+    pub fn new(address: &str, message: MessageType) -> Envelope {
+        Envelope {
+            meta: meta(address),
+            message,
+        }
+    }
+
+    // Parses a frame received from a peer, checking that it speaks a compatible version
+    pub fn parse(frame: &[u8]) -> Result<Envelope, ErrorTypes> {
+        let envelope: Envelope = serde_json::from_slice(frame).map_err(|e| {
+            ErrorTypes::MalformedMessage(format!("Error while parsing message: {}", e))
+        })?;
+
+        if major_version(&envelope.meta.version) != major_version(PROTOCOL_VERSION) {
+            return Err(ErrorTypes::UnsupportedVersion(format!(
+                "Protocol version {} is not compatible with {}",
+                envelope.meta.version, PROTOCOL_VERSION
+            )));
+        }
 
-    serde_json::to_string(&message).unwrap()
+        Ok(envelope)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
 }
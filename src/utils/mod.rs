@@ -1,3 +1,5 @@
+pub mod clock;
+pub mod crypto;
 pub mod hasher;
 pub mod message;
 pub mod reqwest;
@@ -0,0 +1,103 @@
+// What a node keeps of the proposals and votes for the height after the one it is deciding: only
+// messages signed by a validator, and a bounded number per validator, so a peer flooding it with
+// junk cannot push out the messages it will need once it gets there.
+
+use chrono::{DateTime, Duration};
+use no_cap::{
+    blockchain::{
+        commit::{BlockProposal, CommitCertificate},
+        consensus::ConsensusParams,
+        init::Blockchain,
+        mempool::Mempool,
+    },
+    node::Node,
+    p2p::bft::{Bft, BftTimeouts},
+    sim::SimAgent,
+    types::blockchain::{ActionType, AgentRole},
+};
+
+#[tokio::test]
+async fn junk_for_the_next_height_does_not_crowd_out_real_messages() {
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+    let mut validator = SimAgent::from_id("validator", AgentRole::Validator);
+    let genesis = vec![
+        proposer.sign(ActionType::RegisterAgent, "genesis").payload,
+        validator.sign(ActionType::RegisterAgent, "genesis").payload,
+    ];
+
+    // blocks 1 and 2 as the single validator commits them, each finalizing a proposal
+    let mut producer = Blockchain::init(genesis.clone(), ConsensusParams::default());
+    let mut mempool = Mempool::default();
+    let now = DateTime::UNIX_EPOCH;
+    for reasoning_hash in ["first", "second"] {
+        let proposal = proposer.sign(ActionType::ProposeUpdate, reasoning_hash);
+        mempool.insert(proposal.payload, now).unwrap();
+        let vote = validator.sign(ActionType::VoteAccept, reasoning_hash);
+        mempool.insert(vote.payload, now).unwrap();
+    }
+    let mut blocks = Vec::new();
+    for (height, reasoning_hash) in [(1, "first"), (2, "second")] {
+        let mut block = producer
+            .propose_block(
+                &mempool,
+                reasoning_hash,
+                &validator.keypair,
+                0,
+                now + Duration::seconds(height),
+            )
+            .unwrap();
+        block.commit = Some(CommitCertificate {
+            round: 0,
+            precommits: vec![validator.precommit(&block, 0)],
+        });
+        producer.append_block(block.clone(), &mut mempool).unwrap();
+        blocks.push(block);
+    }
+    let (first, second) = (blocks[0].clone(), blocks[1].clone());
+
+    // a follower still deciding height 1
+    let node = Node::new(
+        Blockchain::init(genesis, ConsensusParams::default()),
+        Mempool::default(),
+        "127.0.0.1:0".to_string(),
+        Bft::new(None, BftTimeouts::default()),
+    )
+    .await;
+    let p2p = node.p2p.lock().await;
+
+    // votes for height 2 that the validator never signed, proposals from someone who is not a
+    // validator, and the real precommit sent over and over
+    let mut proposed = second.clone();
+    let real_precommit = proposed.commit.take().unwrap().precommits.remove(0);
+    for round in 0..2_000 {
+        let mut forged = real_precommit.clone();
+        forged.round = round;
+        p2p.handle_vote(forged).await;
+
+        let mut outsider = proposed.clone();
+        outsider.producer = proposer.id.clone();
+        outsider.round = round;
+        p2p.handle_proposal(BlockProposal {
+            round,
+            valid_round: None,
+            block: outsider,
+        })
+        .await;
+
+        p2p.handle_vote(real_precommit.clone()).await;
+    }
+    p2p.handle_proposal(BlockProposal {
+        round: 0,
+        valid_round: None,
+        block: proposed,
+    })
+    .await;
+
+    // once block 1 arrives, what was kept for height 2 is enough to commit it
+    assert!(p2p.handle_block(first).await.is_none());
+    p2p.start_commit().await;
+
+    let blockchain = node.blockchain.lock().await;
+    assert_eq!(blockchain.height(), 2);
+    assert_eq!(blockchain.blocks[2].hash, second.hash);
+}
@@ -0,0 +1,161 @@
+// Test vectors for the canonical block header encoding. The expected values were computed
+// independently of this crate; if one of them changes, every existing chain stops verifying.
+
+use chrono::{DateTime, TimeZone, Utc};
+use no_cap::{
+    blockchain::{
+        block::Block,
+        consensus::{ConsensusParams, Fraction},
+    },
+    utils::hasher::{block_hasher, block_header_bytes},
+};
+
+fn header(
+    index: u32,
+    prev_hash: &str,
+    timestamp: DateTime<Utc>,
+    merkle_root: Option<&str>,
+    producer: &str,
+) -> Block {
+    Block {
+        index,
+        prev_hash: prev_hash.to_string(),
+        hash: None,
+        timestamp,
+        transactions: Vec::new(),
+        merkle_root: merkle_root.map(str::to_string),
+        producer: producer.to_string(),
+        round: 0,
+        signature: String::new(),
+        consensus: None,
+        commit: None,
+    }
+}
+
+const EMPTY_MERKLE_ROOT: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+#[test]
+fn genesis_without_agents() {
+    let block = header(0, "0", DateTime::UNIX_EPOCH, Some(EMPTY_MERKLE_ROOT), "");
+
+    assert_eq!(
+        hex::encode(block_header_bytes(&block)),
+        "0100000000000000013000000000000000000000000000000040653362306334343239386663316331343961\
+         6662663463383939366662393234323761653431653436343962393334636134393539393162373835326238\
+         353500000000"
+    );
+    assert_eq!(
+        block_hasher(&block),
+        "6d66fdb9c1396cc5d18bafdf540bfeb5b7b1b03a4467f693d98d278aa588cf5d"
+    );
+}
+
+#[test]
+fn genesis_with_default_consensus_params() {
+    let mut block = header(0, "0", DateTime::UNIX_EPOCH, Some(EMPTY_MERKLE_ROOT), "");
+    block.consensus = Some(ConsensusParams::default());
+
+    assert_eq!(
+        hex::encode(block_header_bytes(&block)),
+        "0100000000000000013000000000000000000000000000000040653362306334343239386663316331343961\
+         6662663463383939366662393234323761653431653436343962393334636134393539393162373835326238\
+         35350000000001000000020000000300000002000000030000000200000003"
+    );
+    assert_eq!(
+        block_hasher(&block),
+        "57608c60ea38a1bd3b9030dc7c98ac41cebf9483c57a13d5509def3843d65428"
+    );
+    assert_eq!(
+        Block::init(Vec::new(), ConsensusParams::default())
+            .hash
+            .as_deref(),
+        Some(block_hasher(&block).as_str())
+    );
+}
+
+#[test]
+fn genesis_with_custom_consensus_params() {
+    let mut block = header(0, "0", DateTime::UNIX_EPOCH, Some(EMPTY_MERKLE_ROOT), "");
+    block.consensus = Some(ConsensusParams {
+        quorum: Fraction::new(1, 2),
+        accept_threshold: Fraction::new(3, 4),
+        reject_threshold: Fraction::new(1, 1),
+    });
+
+    assert_eq!(
+        block_hasher(&block),
+        "3b9de59ae208b0666fd7a6c082991c84a63fac71ef5675a7b6bce83fc8eb99c1"
+    );
+}
+
+#[test]
+fn block_with_producer_and_nanoseconds() {
+    let timestamp = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap()
+        + chrono::Duration::nanoseconds(123_456_789);
+    let block = header(
+        1,
+        &"ab".repeat(32),
+        timestamp,
+        Some(&"cd".repeat(32)),
+        "10.0.0.1:2373",
+    );
+
+    assert_eq!(
+        hex::encode(block_header_bytes(&block)),
+        "0100000001000000406162616261626162616261626162616261626162616261626162616261626162616261\
+         62616261626162616261626162616261626162616261626162616261620000000065937d25075bcd15000000\
+         4063646364636463646364636463646364636463646364636463646364636463646364636463646364636463\
+         6463646364636463646364636463646364636463640000000d31302e302e302e313a32333733"
+    );
+    assert_eq!(
+        block_hasher(&block),
+        "6b1fae154b02ebe00d7f624250e40a3016d7b94f82c6f01fdca3af87c3dfd2f4"
+    );
+}
+
+#[test]
+fn unset_merkle_root_and_pre_epoch_timestamp() {
+    let timestamp = DateTime::from_timestamp(-1, 500).unwrap();
+    let block = header(7, &"ab".repeat(32), timestamp, None, "");
+
+    assert_eq!(
+        block_hasher(&block),
+        "14c5c6b44d649ae031fb2f37ce0daec5d5f23abd4a27fb7995b4036d290fa43c"
+    );
+}
+
+#[test]
+fn hash_ignores_the_stored_hash() {
+    let mut block = header(3, "00", DateTime::UNIX_EPOCH, Some("ff"), "node");
+    let unsealed = block_hasher(&block);
+
+    block.hash = Some("anything".to_string());
+    assert_eq!(block_hasher(&block), unsealed);
+}
+
+#[test]
+fn hash_covers_merkle_root_and_producer() {
+    let block = header(3, "00", DateTime::UNIX_EPOCH, Some("ff"), "node");
+
+    let mut other_root = block.clone();
+    other_root.merkle_root = Some("fe".to_string());
+    assert_ne!(block_hasher(&other_root), block_hasher(&block));
+
+    let mut other_producer = block.clone();
+    other_producer.producer = "other".to_string();
+    assert_ne!(block_hasher(&other_producer), block_hasher(&block));
+}
+
+#[test]
+fn round_is_only_encoded_after_the_first() {
+    let block = header(3, "00", DateTime::UNIX_EPOCH, Some("ff"), "node");
+    let first_round = hex::encode(block_header_bytes(&block));
+
+    let mut later_round = block.clone();
+    later_round.round = 1;
+    assert_eq!(
+        hex::encode(block_header_bytes(&later_round)),
+        format!("{}0200000001", first_round)
+    );
+    assert_ne!(block_hasher(&later_round), block_hasher(&block));
+}
@@ -0,0 +1,336 @@
+// Who may produce a block: validators take turns by height and round, a block only counts with
+// the signature of the validator whose turn it was and becomes final with the precommits of more
+// than 2/3 of the validators.
+
+use chrono::{DateTime, Duration, Utc};
+use no_cap::{
+    blockchain::{
+        block::Block, commit::CommitCertificate, consensus::ConsensusParams, init::Blockchain,
+        mempool::Mempool,
+    },
+    sim::SimAgent,
+    types::{
+        blockchain::{ActionType, AgentRole},
+        error::ErrorTypes,
+    },
+    utils::crypto::sign_block,
+};
+
+struct Setup {
+    blockchain: Blockchain,
+
+    mempool: Mempool,
+
+    // validator_a, validator_b, in turn order
+    validators: Vec<SimAgent>,
+}
+
+// A chain with two validators and an accepted proposal "update" waiting in the mempool
+fn setup() -> Setup {
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+    // registered out of order, turns follow the agent ids
+    let mut validators = vec![
+        SimAgent::from_id("validator_b", AgentRole::Validator),
+        SimAgent::from_id("validator_a", AgentRole::Validator),
+    ];
+    let mut genesis = vec![proposer.sign(ActionType::RegisterAgent, "genesis").payload];
+    for validator in validators.iter_mut() {
+        genesis.push(validator.sign(ActionType::RegisterAgent, "genesis").payload);
+    }
+    validators.reverse();
+
+    let mut mempool = Mempool::default();
+    let now = DateTime::UNIX_EPOCH;
+    mempool
+        .insert(
+            proposer.sign(ActionType::ProposeUpdate, "update").payload,
+            now,
+        )
+        .unwrap();
+    for validator in validators.iter_mut() {
+        mempool
+            .insert(
+                validator.sign(ActionType::VoteAccept, "update").payload,
+                now,
+            )
+            .unwrap();
+    }
+
+    Setup {
+        blockchain: Blockchain::init(genesis, ConsensusParams::default()),
+        mempool,
+        validators,
+    }
+}
+
+fn timestamp() -> DateTime<Utc> {
+    DateTime::UNIX_EPOCH + Duration::seconds(1)
+}
+
+#[test]
+fn validators_take_turns_by_height() {
+    let Setup { blockchain, .. } = setup();
+    let producers: Vec<&str> = (0..5)
+        .map(|height| {
+            blockchain
+                .registry
+                .producer(height, 0)
+                .map_or("", |record| record.agent_id.as_str())
+        })
+        .collect();
+
+    assert_eq!(
+        producers,
+        vec![
+            "",
+            "validator_a",
+            "validator_b",
+            "validator_a",
+            "validator_b"
+        ]
+    );
+}
+
+#[test]
+fn later_rounds_pass_the_turn_on() {
+    let Setup { blockchain, .. } = setup();
+    let producers: Vec<&str> = (0..3)
+        .map(|round| {
+            blockchain
+                .registry
+                .producer(2, round)
+                .map_or("", |record| record.agent_id.as_str())
+        })
+        .collect();
+
+    assert_eq!(producers, vec!["validator_b", "validator_a", "validator_b"]);
+}
+
+#[test]
+fn block_is_signed_by_the_validator_whose_turn_it_is() {
+    let setup = setup();
+
+    let block = setup
+        .blockchain
+        .propose_block(
+            &setup.mempool,
+            "update",
+            &setup.validators[0].keypair,
+            0,
+            timestamp(),
+        )
+        .unwrap();
+
+    assert_eq!(block.producer, "validator_a");
+    assert_eq!(block.round, 0);
+    assert!(!block.signature.is_empty());
+    setup.blockchain.validate_proposed_block(&block).unwrap();
+}
+
+#[test]
+fn validator_out_of_turn_cannot_propose() {
+    let setup = setup();
+
+    let err = setup
+        .blockchain
+        .propose_block(
+            &setup.mempool,
+            "update",
+            &setup.validators[1].keypair,
+            0,
+            timestamp(),
+        )
+        .unwrap_err();
+    assert!(matches!(err, ErrorTypes::Unauthorized(_)));
+
+    // round 1 is validator_b's turn
+    let block = setup
+        .blockchain
+        .propose_block(
+            &setup.mempool,
+            "update",
+            &setup.validators[1].keypair,
+            1,
+            timestamp(),
+        )
+        .unwrap();
+    assert_eq!(block.producer, "validator_b");
+    setup.blockchain.validate_proposed_block(&block).unwrap();
+}
+
+#[test]
+fn block_finalizing_a_proposal_without_its_votes_is_not_valid() {
+    let mut setup = setup();
+    let pending: Vec<_> = setup.mempool.iter().cloned().collect();
+    setup.mempool = Mempool::default();
+    setup
+        .mempool
+        .insert(pending[0].clone(), DateTime::UNIX_EPOCH)
+        .unwrap();
+
+    let block = setup
+        .blockchain
+        .propose_block(
+            &setup.mempool,
+            "update",
+            &setup.validators[0].keypair,
+            0,
+            timestamp(),
+        )
+        .unwrap();
+    let err = setup
+        .blockchain
+        .validate_proposed_block(&block)
+        .unwrap_err();
+
+    assert!(matches!(err, ErrorTypes::InvalidBlock(_)));
+}
+
+// Block 1 for "update" proposed by validator_a in round 0
+fn proposed(setup: &Setup) -> Block {
+    setup
+        .blockchain
+        .propose_block(
+            &setup.mempool,
+            "update",
+            &setup.validators[0].keypair,
+            0,
+            timestamp(),
+        )
+        .unwrap()
+}
+
+#[test]
+fn block_precommitted_by_a_quorum_is_appended() {
+    let mut setup = setup();
+    let mut block = proposed(&setup);
+    block.commit = Some(CommitCertificate {
+        round: 0,
+        precommits: vec![
+            setup.validators[0].precommit(&block, 0),
+            setup.validators[1].precommit(&block, 0),
+        ],
+    });
+
+    setup
+        .blockchain
+        .append_block(block.clone(), &mut setup.mempool)
+        .unwrap();
+
+    assert_eq!(setup.blockchain.height(), 1);
+    assert!(setup.mempool.is_empty());
+    assert!(setup.blockchain.verify().is_valid());
+}
+
+#[test]
+fn block_without_commit_is_rejected() {
+    let setup = setup();
+    let block = proposed(&setup);
+
+    let err = setup.blockchain.validate_next_block(&block).unwrap_err();
+
+    assert!(err.reason().contains("InvalidCommit"), "{:?}", err);
+}
+
+#[test]
+fn commit_short_of_a_quorum_is_rejected() {
+    let setup = setup();
+    let mut block = proposed(&setup);
+    let vote = setup.validators[0].precommit(&block, 0);
+    // with two validators both have to precommit, one of them twice does not do
+    block.commit = Some(CommitCertificate {
+        round: 0,
+        precommits: vec![vote.clone(), vote],
+    });
+
+    let err = setup.blockchain.validate_next_block(&block).unwrap_err();
+
+    assert!(err.reason().contains("InvalidCommit"), "{:?}", err);
+}
+
+#[test]
+fn precommits_from_different_rounds_do_not_commit() {
+    let setup = setup();
+    let mut block = proposed(&setup);
+    block.commit = Some(CommitCertificate {
+        round: 0,
+        precommits: vec![
+            setup.validators[0].precommit(&block, 0),
+            setup.validators[1].precommit(&block, 1),
+        ],
+    });
+
+    let err = setup.blockchain.validate_next_block(&block).unwrap_err();
+
+    assert!(err.reason().contains("InvalidCommit"), "{:?}", err);
+}
+
+#[test]
+fn forged_precommit_is_rejected() {
+    let setup = setup();
+    let mut block = proposed(&setup);
+    let mut forged = setup.validators[0].precommit(&block, 0);
+    forged.validator = setup.validators[1].id.clone();
+    block.commit = Some(CommitCertificate {
+        round: 0,
+        precommits: vec![setup.validators[0].precommit(&block, 0), forged],
+    });
+
+    let err = setup.blockchain.validate_next_block(&block).unwrap_err();
+
+    assert!(err.reason().contains("InvalidCommit"), "{:?}", err);
+}
+
+// Block 1 built by hand and signed with `keypair`, claiming to come from `producer`
+fn forged_block(setup: &Setup, producer: &str, keypair_of: usize) -> Block {
+    let genesis = setup.blockchain.get_last_block().unwrap();
+    let mut block = Block::new(
+        1,
+        0,
+        genesis.hash.unwrap(),
+        producer.to_string(),
+        setup.mempool.iter().cloned().collect(),
+        timestamp(),
+    );
+    sign_block(&mut block, &setup.validators[keypair_of].keypair).unwrap();
+    block
+}
+
+#[test]
+fn block_from_the_wrong_producer_is_rejected() {
+    let setup = setup();
+    let block = forged_block(&setup, "validator_b", 1);
+
+    let err = setup.blockchain.validate_next_block(&block).unwrap_err();
+
+    assert!(err.reason().contains("WrongProducer"), "{:?}", err);
+}
+
+#[test]
+fn block_with_a_bad_signature_is_rejected() {
+    let setup = setup();
+    let block = forged_block(&setup, "validator_a", 1);
+
+    let err = setup.blockchain.validate_next_block(&block).unwrap_err();
+
+    assert!(err.reason().contains("InvalidBlockSignature"), "{:?}", err);
+}
+
+#[test]
+fn chain_without_validators_accepts_no_blocks() {
+    let mut setup = setup();
+    setup.blockchain = Blockchain::init(
+        setup.blockchain.blocks[0]
+            .transactions
+            .iter()
+            .filter(|tx| tx.agent_id == "proposer")
+            .cloned()
+            .collect(),
+        ConsensusParams::default(),
+    );
+    let block = forged_block(&setup, "validator_a", 0);
+
+    let err = setup.blockchain.validate_next_block(&block).unwrap_err();
+
+    assert!(err.reason().contains("WrongProducer"), "{:?}", err);
+}
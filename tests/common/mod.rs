@@ -0,0 +1,247 @@
+// An in-process cluster of nodes on ephemeral localhost ports. Transactions go in and state comes
+// out through each node's HTTP API, the nodes talk to each other over real P2P connections.
+
+use no_cap::{
+    blockchain::{consensus::ConsensusParams, init::Blockchain, mempool::Mempool},
+    node::Node,
+    p2p::bft::{Bft, BftTimeouts},
+    sim::SimAgent as Agent,
+    types::blockchain::{ActionType, AgentRole, Transaction, TransactionMessage},
+    utils::crypto::generate_keypair,
+};
+use serde_json::Value;
+use std::{future::Future, time::Duration};
+use tokio::net::TcpListener;
+
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TestNode {
+    pub node: Node,
+
+    // base URL of the HTTP API
+    pub http: String,
+
+    pub p2p: String,
+}
+
+pub struct Cluster {
+    pub nodes: Vec<TestNode>,
+
+    pub agents: Vec<Agent>,
+
+    genesis: Vec<Transaction>,
+
+    client: reqwest::Client,
+}
+
+impl Cluster {
+    // `node_count` nodes sharing a genesis block that registers `agents`, every node connected
+    // to every other one
+    pub async fn start(node_count: usize, agents: &[(&str, AgentRole)]) -> Cluster {
+        let mut cluster = Cluster::empty(agents);
+        for _ in 0..node_count {
+            cluster.add_node().await;
+        }
+        cluster.wait_connected().await;
+
+        cluster
+    }
+
+    // Node i connected only to the earlier nodes in `links[i]`, for messages that have to be
+    // relayed to get anywhere
+    pub async fn start_linked(links: &[&[usize]], agents: &[(&str, AgentRole)]) -> Cluster {
+        let mut cluster = Cluster::empty(agents);
+        for peers in links {
+            cluster.add_linked_node(peers).await;
+        }
+        for (node, peers) in links.iter().enumerate() {
+            let expected = peers.len() as u64
+                + links.iter().filter(|others| others.contains(&node)).count() as u64;
+            eventually(
+                &format!("node {} to see {} peers", node, expected),
+                || async { cluster.status(node).await["peer_count"].as_u64().unwrap() >= expected },
+            )
+            .await;
+        }
+
+        cluster
+    }
+
+    fn empty(agents: &[(&str, AgentRole)]) -> Cluster {
+        let mut agents: Vec<Agent> = agents
+            .iter()
+            .map(|(id, role)| Agent::new(id, role.clone(), generate_keypair()))
+            .collect();
+        let genesis = agents
+            .iter_mut()
+            .map(|agent| agent.sign(ActionType::RegisterAgent, "genesis").payload)
+            .collect();
+
+        Cluster {
+            nodes: Vec::new(),
+            agents,
+            genesis,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // Starts one more node and connects it to all the others, returns its position
+    pub async fn add_node(&mut self) -> usize {
+        let peers: Vec<usize> = (0..self.nodes.len()).collect();
+        self.add_linked_node(&peers).await
+    }
+
+    // Starts one more node and connects it to `peers`, returns its position. Node i votes on
+    // blocks as the i-th validator in the agents, if there is one.
+    pub async fn add_linked_node(&mut self, peers: &[usize]) -> usize {
+        let http_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let p2p_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http = format!("http://{}", http_listener.local_addr().unwrap());
+        let p2p = p2p_listener.local_addr().unwrap().to_string();
+
+        let blockchain = Blockchain::init(self.genesis.clone(), ConsensusParams::default());
+        let key = self
+            .agents
+            .iter()
+            .filter(|agent| agent.role == AgentRole::Validator)
+            .nth(self.nodes.len())
+            .map(|agent| agent.keypair.clone());
+        let bft = Bft::new(key, BftTimeouts::default());
+        let node = Node::new(blockchain, Mempool::default(), p2p.clone(), bft).await;
+        node.spawn_maintenance();
+        tokio::spawn(node.clone().serve_http(http_listener));
+        tokio::spawn(node.clone().serve_p2p(p2p_listener));
+        for &peer in peers {
+            node.spawn_peer(self.nodes[peer].p2p.clone());
+        }
+
+        self.nodes.push(TestNode { node, http, p2p });
+        self.nodes.len() - 1
+    }
+
+    pub fn agent(&mut self, id: &str) -> &mut Agent {
+        self.agents.iter_mut().find(|agent| agent.id == id).unwrap()
+    }
+
+    // Signs a transaction as `agent` and posts it to `node`, returning the status code and body
+    pub async fn submit(
+        &mut self,
+        node: usize,
+        agent: &str,
+        action_type: ActionType,
+        reasoning_hash: &str,
+    ) -> (u16, Value) {
+        let tx_msg = self.agent(agent).sign(action_type, reasoning_hash);
+        self.post(node, &tx_msg).await
+    }
+
+    // Posts an already signed transaction to `node`, returning the status code and body
+    pub async fn post(&self, node: usize, tx_msg: &TransactionMessage) -> (u16, Value) {
+        let response = self
+            .client
+            .post(format!("{}/transaction", self.nodes[node].http))
+            .json(tx_msg)
+            .send()
+            .await
+            .unwrap();
+
+        (response.status().as_u16(), response.json().await.unwrap())
+    }
+
+    pub async fn get(&self, node: usize, path: &str) -> Option<Value> {
+        let response = self
+            .client
+            .get(format!("{}{}", self.nodes[node].http, path))
+            .send()
+            .await
+            .unwrap();
+
+        response
+            .status()
+            .is_success()
+            .then_some(response.json().await.unwrap())
+    }
+
+    pub async fn status(&self, node: usize) -> Value {
+        self.get(node, "/status").await.unwrap()
+    }
+
+    pub async fn height(&self, node: usize) -> u64 {
+        self.status(node).await["height"].as_u64().unwrap()
+    }
+
+    // Status of the proposal as `node` sees it, `None` if it does not know the proposal
+    pub async fn proposal_status(&self, node: usize, reasoning_hash: &str) -> Option<String> {
+        self.get(node, &format!("/proposals/{}", reasoning_hash))
+            .await
+            .map(|proposal| proposal["status"].as_str().unwrap().to_string())
+    }
+
+    pub async fn wait_connected(&self) {
+        let expected = self.nodes.len() as u64 - 1;
+        for node in 0..self.nodes.len() {
+            eventually(
+                &format!("node {} to see {} peers", node, expected),
+                || async { self.status(node).await["peer_count"].as_u64().unwrap() >= expected },
+            )
+            .await;
+        }
+    }
+
+    pub async fn wait_for_height(&self, height: u64) {
+        for node in 0..self.nodes.len() {
+            eventually(
+                &format!("node {} to reach height {}", node, height),
+                || async { self.height(node).await >= height },
+            )
+            .await;
+        }
+    }
+
+    // Every node reports the same height and tip and holds the same blocks
+    pub async fn assert_converged(&self) {
+        let first = self.status(0).await;
+        let first_chain = self.block_hashes(0).await;
+        for node in 1..self.nodes.len() {
+            let status = self.status(node).await;
+            assert_eq!(status["height"], first["height"], "height of node {}", node);
+            assert_eq!(
+                status["last_block_hash"], first["last_block_hash"],
+                "tip of node {}",
+                node
+            );
+            assert_eq!(
+                self.block_hashes(node).await,
+                first_chain,
+                "chain of node {}",
+                node
+            );
+        }
+    }
+
+    async fn block_hashes(&self, node: usize) -> Vec<Option<String>> {
+        let blockchain = self.nodes[node].node.blockchain.lock().await;
+        blockchain
+            .blocks
+            .iter()
+            .map(|block| block.hash.clone())
+            .collect()
+    }
+}
+
+// Polls `check` until it holds, failing the test after `WAIT_TIMEOUT`
+pub async fn eventually<F, Fut>(what: &str, mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = tokio::time::Instant::now() + WAIT_TIMEOUT;
+    while !check().await {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "timed out waiting for {}",
+            what
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -0,0 +1,81 @@
+// Parsing of the config file and the flags on top of it. Anything the node does not know is an
+// error rather than ignored, a misspelled key would otherwise silently fall back to its default.
+
+use clap::Parser;
+use no_cap::{
+    blockchain::consensus::Fraction,
+    types::{args::Args, config::Config, error::ErrorTypes},
+};
+
+#[test]
+fn shipped_config_file_loads() {
+    let config = Config::load("config/Config.toml").unwrap();
+
+    config.validate().unwrap();
+}
+
+#[test]
+fn unknown_section_is_rejected() {
+    let err = toml::from_str::<Config>("[consenus]\nquorum = \"1/2\"\n").unwrap_err();
+
+    assert!(err.to_string().contains("consenus"), "{}", err);
+}
+
+#[test]
+fn unknown_consensus_key_is_rejected() {
+    let err = toml::from_str::<Config>("[consensus]\nquorom = \"1/2\"\n").unwrap_err();
+
+    assert!(err.to_string().contains("quorom"), "{}", err);
+}
+
+fn resolve(flags: &[&str]) -> Result<Config, ErrorTypes> {
+    let args = [
+        "no_cap",
+        "--config",
+        "config/Config.toml",
+        "--log-config",
+        "config/log_config.yml",
+    ];
+    Config::resolve(&Args::parse_from(args.iter().chain(flags)))
+}
+
+#[test]
+fn flags_set_the_listen_addresses_and_thresholds() {
+    let config = resolve(&[
+        "--http-listen",
+        "127.0.0.1:4000",
+        "--p2p-listen",
+        "127.0.0.1:4001",
+        "--quorum",
+        "1/2",
+        "--accept-threshold",
+        "3/4",
+        "--reject-threshold",
+        "1/2",
+    ])
+    .unwrap();
+
+    assert_eq!(config.http.listen.to_string(), "127.0.0.1:4000");
+    assert_eq!(config.p2p.listen.to_string(), "127.0.0.1:4001");
+    assert_eq!(config.consensus.quorum, Fraction::new(1, 2));
+    assert_eq!(config.consensus.accept_threshold, Fraction::new(3, 4));
+    assert_eq!(config.consensus.reject_threshold, Fraction::new(1, 2));
+}
+
+#[test]
+fn flags_are_validated_like_the_config_file() {
+    let err = resolve(&[
+        "--http-listen",
+        "127.0.0.1:4000",
+        "--p2p-listen",
+        "0.0.0.0:4000",
+        "--accept-threshold",
+        "1/2",
+        "--reject-threshold",
+        "1/2",
+    ])
+    .unwrap_err();
+
+    assert!(err.reason().contains("both use"), "{:?}", err);
+    assert!(err.reason().contains("add up to more than 1"), "{:?}", err);
+}
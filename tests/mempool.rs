@@ -0,0 +1,303 @@
+// Limits, deduplication and revalidation of the mempool, without any node around it.
+
+use chrono::{DateTime, Duration, Utc};
+use no_cap::{
+    blockchain::{
+        block::Block,
+        commit::CommitCertificate,
+        consensus::ConsensusParams,
+        init::Blockchain,
+        mempool::{Mempool, MempoolLimits},
+    },
+    sim::SimAgent,
+    types::{
+        blockchain::{ActionType, AgentRole, Transaction},
+        error::ErrorTypes,
+    },
+    utils::hasher::transaction_hash,
+};
+
+fn sign(agent: &mut SimAgent, action_type: ActionType, reasoning_hash: &str) -> Transaction {
+    agent.sign(action_type, reasoning_hash).payload
+}
+
+fn limited(max_transactions: usize) -> Mempool {
+    Mempool::new(MempoolLimits {
+        max_transactions,
+        ..MempoolLimits::default()
+    })
+}
+
+fn start() -> DateTime<Utc> {
+    DateTime::UNIX_EPOCH
+}
+
+#[test]
+fn same_transaction_is_only_kept_once() {
+    let mut mempool = Mempool::default();
+    let tx = sign(
+        &mut SimAgent::from_id("proposer", AgentRole::Proposer),
+        ActionType::ProposeUpdate,
+        "a",
+    );
+
+    mempool.insert(tx.clone(), start()).unwrap();
+    let err = mempool.insert(tx.clone(), start()).unwrap_err();
+
+    assert!(matches!(err, ErrorTypes::DuplicateTransaction(_)));
+    assert_eq!(mempool.len(), 1);
+    assert!(mempool.contains(&transaction_hash(&tx)));
+}
+
+#[test]
+fn full_mempool_evicts_the_oldest_proposal_as_a_whole() {
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let mut mempool = limited(3);
+
+    mempool
+        .insert(
+            sign(&mut proposer, ActionType::ProposeUpdate, "old"),
+            start(),
+        )
+        .unwrap();
+    mempool
+        .insert(sign(&mut evaluator, ActionType::VoteAccept, "old"), start())
+        .unwrap();
+    mempool
+        .insert(
+            sign(&mut proposer, ActionType::ProposeUpdate, "new"),
+            start(),
+        )
+        .unwrap();
+    let dropped = mempool
+        .insert(sign(&mut evaluator, ActionType::VoteAccept, "new"), start())
+        .unwrap();
+
+    assert_eq!(dropped, vec!["old".to_string()]);
+    assert_eq!(mempool.len(), 2);
+    assert_eq!(mempool.proposal("old").count(), 0);
+    assert_eq!(mempool.proposal("new").count(), 2);
+}
+
+#[test]
+fn proposal_filling_the_mempool_is_not_evicted_for_its_own_votes() {
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let mut mempool = limited(1);
+
+    mempool
+        .insert(sign(&mut proposer, ActionType::ProposeUpdate, "a"), start())
+        .unwrap();
+    let err = mempool
+        .insert(sign(&mut evaluator, ActionType::VoteAccept, "a"), start())
+        .unwrap_err();
+
+    assert!(matches!(err, ErrorTypes::MempoolFull(_)));
+    assert_eq!(mempool.proposal("a").count(), 1);
+}
+
+#[test]
+fn idle_proposals_and_old_registrations_expire() {
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let mut newcomer = SimAgent::from_id("newcomer", AgentRole::Evaluator);
+    let mut mempool = Mempool::default();
+    let max_age = Duration::seconds(mempool.limits.max_age_secs as i64);
+
+    mempool
+        .insert(
+            sign(&mut newcomer, ActionType::RegisterAgent, "join"),
+            start(),
+        )
+        .unwrap();
+    mempool
+        .insert(
+            sign(&mut proposer, ActionType::ProposeUpdate, "idle"),
+            start(),
+        )
+        .unwrap();
+    mempool
+        .insert(
+            sign(&mut proposer, ActionType::ProposeUpdate, "busy"),
+            start(),
+        )
+        .unwrap();
+    // a late vote keeps the proposal alive
+    mempool
+        .insert(
+            sign(&mut evaluator, ActionType::VoteAccept, "busy"),
+            start() + max_age,
+        )
+        .unwrap();
+
+    let dropped = mempool.expire(start() + max_age + Duration::seconds(1));
+
+    assert_eq!(dropped, vec!["idle".to_string()]);
+    assert_eq!(mempool.proposal("busy").count(), 2);
+    assert_eq!(mempool.len(), 2);
+}
+
+#[test]
+fn proposal_expires_counting_from_its_last_activity_still_pending() {
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let mut mempool = Mempool::default();
+    let max_age = Duration::seconds(mempool.limits.max_age_secs as i64);
+
+    mempool
+        .insert(
+            sign(&mut proposer, ActionType::ProposeUpdate, "busy"),
+            start(),
+        )
+        .unwrap();
+    let early = sign(&mut evaluator, ActionType::VoteReject, "busy");
+    mempool.insert(early.clone(), start()).unwrap();
+    let late = sign(&mut evaluator, ActionType::VoteAccept, "busy");
+    mempool.insert(late.clone(), start() + max_age).unwrap();
+    assert_eq!(mempool.last_nonce("evaluator"), Some(late.nonce));
+
+    // the late vote went into a block, the proposal has been idle since its first arrivals
+    mempool.remove(&transaction_hash(&late));
+    assert_eq!(mempool.last_nonce("evaluator"), Some(early.nonce));
+    let dropped = mempool.expire(start() + max_age + Duration::seconds(1));
+
+    assert_eq!(dropped, vec!["busy".to_string()]);
+    assert!(mempool.is_empty());
+    assert_eq!(mempool.last_nonce("evaluator"), None);
+}
+
+#[test]
+fn revalidation_drops_what_the_chain_no_longer_allows() {
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+    let mut stranger = SimAgent::from_id("stranger", AgentRole::Proposer);
+    let genesis = vec![sign(&mut proposer, ActionType::RegisterAgent, "genesis")];
+    let blockchain = Blockchain::init(genesis, ConsensusParams::default());
+    let mut mempool = Mempool::default();
+
+    let kept = sign(&mut proposer, ActionType::ProposeUpdate, "kept");
+    mempool.insert(kept.clone(), start()).unwrap();
+    mempool
+        .insert(
+            sign(&mut stranger, ActionType::ProposeUpdate, "unauthorized"),
+            start(),
+        )
+        .unwrap();
+
+    let dropped = mempool.revalidate(&blockchain);
+
+    assert_eq!(dropped, vec!["unauthorized".to_string()]);
+    assert_eq!(
+        mempool.iter().map(transaction_hash).collect::<Vec<_>>(),
+        vec![transaction_hash(&kept)]
+    );
+}
+
+// A chain registering `proposer` and `validator`, with "first" proposed before "second"
+struct TwoProposals {
+    blockchain: Blockchain,
+
+    mempool: Mempool,
+
+    validator: SimAgent,
+
+    first: Transaction,
+}
+
+fn two_proposals() -> TwoProposals {
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+    let mut validator = SimAgent::from_id("validator", AgentRole::Validator);
+    let genesis = vec![
+        sign(&mut proposer, ActionType::RegisterAgent, "genesis"),
+        sign(&mut validator, ActionType::RegisterAgent, "genesis"),
+    ];
+    let blockchain = Blockchain::init(genesis, ConsensusParams::default());
+    let mut mempool = Mempool::default();
+
+    let first = sign(&mut proposer, ActionType::ProposeUpdate, "first");
+    mempool.insert(first.clone(), start()).unwrap();
+    mempool
+        .insert(
+            sign(&mut proposer, ActionType::ProposeUpdate, "second"),
+            start(),
+        )
+        .unwrap();
+    mempool
+        .insert(
+            sign(&mut validator, ActionType::VoteAccept, "second"),
+            start(),
+        )
+        .unwrap();
+
+    TwoProposals {
+        blockchain,
+        mempool,
+        validator,
+        first,
+    }
+}
+
+// The next block for `reasoning_hash` from what `mempool` holds, committed by the validator
+fn commit(setup: &TwoProposals, mempool: &Mempool, reasoning_hash: &str) -> Block {
+    let mut block = setup
+        .blockchain
+        .propose_block(
+            mempool,
+            reasoning_hash,
+            &setup.validator.keypair,
+            0,
+            start() + Duration::seconds(setup.blockchain.height() as i64 + 1),
+        )
+        .unwrap();
+    block.commit = Some(CommitCertificate {
+        round: 0,
+        precommits: vec![setup.validator.precommit(&block, 0)],
+    });
+    block
+}
+
+#[test]
+fn proposal_with_a_nonce_the_chain_moved_past_is_dropped() {
+    let mut setup = two_proposals();
+    let block = commit(&setup, &setup.mempool, "second");
+    setup
+        .blockchain
+        .append_block(block, &mut setup.mempool)
+        .unwrap();
+
+    let dropped = setup.mempool.revalidate(&setup.blockchain);
+
+    assert_eq!(dropped, vec!["first".to_string()]);
+    assert!(setup.mempool.is_empty());
+}
+
+#[test]
+fn block_replaying_a_lower_unused_nonce_is_not_valid() {
+    let mut setup = two_proposals();
+    let block = commit(&setup, &setup.mempool, "second");
+    setup
+        .blockchain
+        .append_block(block, &mut setup.mempool)
+        .unwrap();
+
+    // "first" was signed before "second", its nonce was never used but is below the chain's
+    let mut replay = Mempool::default();
+    replay.insert(setup.first.clone(), start()).unwrap();
+    replay
+        .insert(
+            setup
+                .validator
+                .sign(ActionType::VoteAccept, "first")
+                .payload,
+            start(),
+        )
+        .unwrap();
+    let block = commit(&setup, &replay, "first");
+
+    let err = setup
+        .blockchain
+        .validate_proposed_block(&block)
+        .unwrap_err();
+
+    assert!(err.reason().contains("ReplayedNonce"), "{:?}", err);
+}
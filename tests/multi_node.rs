@@ -0,0 +1,201 @@
+// Several nodes in one process, driven through their HTTP APIs. With three voters and the
+// default 2/3 quorum and thresholds, two matching votes decide a proposal. Node 0 and node 1
+// commit blocks as validator_1 and validator_2, any other node only follows.
+
+mod common;
+
+use common::Cluster;
+use no_cap::types::blockchain::{ActionType, AgentRole};
+
+const AGENTS: &[(&str, AgentRole)] = &[
+    ("proposer", AgentRole::Proposer),
+    ("validator_1", AgentRole::Validator),
+    ("validator_2", AgentRole::Validator),
+    ("evaluator_3", AgentRole::Evaluator),
+];
+
+#[tokio::test(flavor = "multi_thread")]
+async fn accepted_proposal_is_finalized_on_every_node() {
+    let mut cluster = Cluster::start(3, AGENTS).await;
+
+    let (status, _) = cluster
+        .submit(0, "proposer", ActionType::ProposeUpdate, "update-1")
+        .await;
+    assert_eq!(status, 202);
+    cluster
+        .submit(0, "validator_1", ActionType::VoteAccept, "update-1")
+        .await;
+    let (status, receipt) = cluster
+        .submit(0, "validator_2", ActionType::VoteAccept, "update-1")
+        .await;
+    assert_eq!(status, 202);
+
+    // the block is committed once both validators precommitted it
+    cluster.wait_for_height(1).await;
+    cluster.assert_converged().await;
+    let included = cluster
+        .get(
+            2,
+            &format!("/transactions/{}/status", receipt["id"].as_str().unwrap()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(included["status"], "included");
+    assert_eq!(included["block_index"], 1);
+    let block = cluster.get(2, "/blocks/1").await.unwrap();
+    assert_eq!(block["commit"]["precommits"].as_array().unwrap().len(), 2);
+    for node in 0..3 {
+        assert_eq!(
+            cluster.proposal_status(node, "update-1").await.as_deref(),
+            Some("Finalized"),
+            "proposal on node {}",
+            node
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rejected_proposal_produces_no_block() {
+    let mut cluster = Cluster::start(2, AGENTS).await;
+
+    cluster
+        .submit(1, "proposer", ActionType::ProposeUpdate, "update-1")
+        .await;
+    cluster
+        .submit(1, "validator_1", ActionType::VoteReject, "update-1")
+        .await;
+    cluster
+        .submit(1, "evaluator_3", ActionType::VoteReject, "update-1")
+        .await;
+
+    assert_eq!(
+        cluster.proposal_status(1, "update-1").await.as_deref(),
+        Some("Rejected")
+    );
+    assert_eq!(cluster.height(0).await, 0);
+    assert_eq!(cluster.height(1).await, 0);
+    cluster.assert_converged().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proposal_below_quorum_stays_open() {
+    let mut cluster = Cluster::start(2, AGENTS).await;
+
+    cluster
+        .submit(0, "proposer", ActionType::ProposeUpdate, "update-1")
+        .await;
+    let (status, receipt) = cluster
+        .submit(0, "validator_1", ActionType::VoteAccept, "update-1")
+        .await;
+    assert_eq!(status, 202);
+    assert_eq!(receipt["status"], "pending");
+
+    assert_eq!(
+        cluster.proposal_status(0, "update-1").await.as_deref(),
+        Some("Voting")
+    );
+    assert_eq!(cluster.status(0).await["mempool_size"], 2);
+    assert_eq!(cluster.height(0).await, 0);
+    cluster.assert_converged().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proposer_cannot_vote() {
+    let mut cluster = Cluster::start(1, AGENTS).await;
+
+    cluster
+        .submit(0, "proposer", ActionType::ProposeUpdate, "update-1")
+        .await;
+    let (status, rejection) = cluster
+        .submit(0, "proposer", ActionType::VoteAccept, "update-1")
+        .await;
+
+    assert_eq!(status, 403);
+    assert_eq!(rejection["code"], "unauthorized");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resubmitted_transaction_is_not_reported_as_rejected() {
+    let mut cluster = Cluster::start(2, AGENTS).await;
+
+    let first = cluster
+        .agent("proposer")
+        .sign(ActionType::ProposeUpdate, "update-1");
+    let (status, receipt) = cluster.post(0, &first).await;
+    assert_eq!(status, 202);
+    let (status, rejection) = cluster.post(0, &first).await;
+    assert_eq!(status, 409);
+    assert_eq!(rejection["code"], "duplicate_transaction");
+
+    // a later proposal of the same proposer is finalized, which drops the first one from the
+    // mempool without it ever being rejected
+    cluster
+        .submit(0, "proposer", ActionType::ProposeUpdate, "update-2")
+        .await;
+    cluster
+        .submit(0, "validator_1", ActionType::VoteAccept, "update-2")
+        .await;
+    cluster
+        .submit(0, "validator_2", ActionType::VoteAccept, "update-2")
+        .await;
+    cluster.wait_for_height(1).await;
+
+    let status = cluster
+        .get(
+            0,
+            &format!("/transactions/{}/status", receipt["id"].as_str().unwrap()),
+        )
+        .await;
+    assert!(status.is_none(), "{:?}", status);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn late_node_catches_up() {
+    let mut cluster = Cluster::start(2, AGENTS).await;
+
+    for update in ["update-1", "update-2"] {
+        cluster
+            .submit(0, "proposer", ActionType::ProposeUpdate, update)
+            .await;
+        cluster
+            .submit(0, "validator_1", ActionType::VoteAccept, update)
+            .await;
+        cluster
+            .submit(0, "validator_2", ActionType::VoteAccept, update)
+            .await;
+    }
+    cluster.wait_for_height(2).await;
+
+    let late = cluster.add_node().await;
+    cluster.wait_connected().await;
+    cluster.wait_for_height(2).await;
+
+    cluster.assert_converged().await;
+    assert_eq!(
+        cluster.proposal_status(late, "update-2").await.as_deref(),
+        Some("Finalized")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_are_relayed_between_nodes_that_are_not_peers() {
+    // the two validators only reach each other through node 2
+    let mut cluster = Cluster::start_linked(&[&[], &[], &[0, 1]], AGENTS).await;
+
+    cluster
+        .submit(0, "proposer", ActionType::ProposeUpdate, "update-1")
+        .await;
+    cluster
+        .submit(0, "validator_1", ActionType::VoteAccept, "update-1")
+        .await;
+    cluster
+        .submit(0, "validator_2", ActionType::VoteAccept, "update-1")
+        .await;
+
+    cluster.wait_for_height(1).await;
+    cluster.assert_converged().await;
+    assert_eq!(
+        cluster.proposal_status(1, "update-1").await.as_deref(),
+        Some("Finalized")
+    );
+}
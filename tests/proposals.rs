@@ -0,0 +1,64 @@
+// Proposal lifecycle on a single node. A proposal is accepted on the votes in the mempool, so it
+// has to be decided again whenever a committed block changes what those votes count for.
+
+use chrono::Utc;
+use no_cap::{
+    blockchain::{
+        commit::CommitCertificate, consensus::ConsensusParams, init::Blockchain, mempool::Mempool,
+    },
+    node::Node,
+    p2p::bft::{Bft, BftTimeouts},
+    sim::SimAgent,
+    types::blockchain::{ActionType, AgentRole, ProposalStatus},
+};
+
+#[tokio::test]
+async fn accepted_proposal_goes_back_to_voting_when_the_electorate_grows() {
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+    let mut validator = SimAgent::from_id("validator", AgentRole::Validator);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let genesis = vec![
+        proposer.sign(ActionType::RegisterAgent, "genesis").payload,
+        validator.sign(ActionType::RegisterAgent, "genesis").payload,
+        evaluator.sign(ActionType::RegisterAgent, "genesis").payload,
+    ];
+    let blockchain = Blockchain::init(genesis, ConsensusParams::default());
+    // without a validator key the node follows the chain but never commits on its own
+    let node = Node::new(
+        blockchain.clone(),
+        Mempool::default(),
+        "127.0.0.1:0".to_string(),
+        Bft::new(None, BftTimeouts::default()),
+    )
+    .await;
+
+    let p2p = node.p2p.lock().await;
+    for tx_msg in [
+        proposer.sign(ActionType::ProposeUpdate, "update"),
+        validator.sign(ActionType::VoteAccept, "update"),
+        evaluator.sign(ActionType::VoteAccept, "update"),
+    ] {
+        p2p.handle_transaction(tx_msg).await.unwrap();
+    }
+    let status = p2p.proposal_status("update").await.unwrap().status;
+    assert_eq!(status, ProposalStatus::Accepted);
+
+    // two more evaluators join, the two votes are no longer a quorum of the four voters
+    let mut joining = Mempool::default();
+    for id in ["newcomer_a", "newcomer_b"] {
+        let mut newcomer = SimAgent::from_id(id, AgentRole::Evaluator);
+        let tx = newcomer.sign_admitted(validator.admit(&newcomer)).payload;
+        joining.insert(tx, Utc::now()).unwrap();
+    }
+    let mut block = blockchain
+        .propose_block(&joining, "", &validator.keypair, 0, Utc::now())
+        .unwrap();
+    block.commit = Some(CommitCertificate {
+        round: 0,
+        precommits: vec![validator.precommit(&block, 0)],
+    });
+    assert!(p2p.handle_block(block).await.is_none());
+
+    let status = p2p.proposal_status("update").await.unwrap().status;
+    assert_eq!(status, ProposalStatus::Voting);
+}
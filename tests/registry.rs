@@ -0,0 +1,96 @@
+// Who may join after genesis: proposers register themselves, evaluators and validators only with
+// the admission of a registered validator.
+
+use no_cap::{
+    blockchain::{consensus::ConsensusParams, init::Blockchain},
+    sim::SimAgent,
+    types::{
+        blockchain::{ActionType, AgentRole},
+        error::ErrorTypes,
+    },
+};
+
+// A chain whose genesis registers `validator` and `evaluator`
+fn chain(validator: &mut SimAgent, evaluator: &mut SimAgent) -> Blockchain {
+    let genesis = vec![
+        validator.sign(ActionType::RegisterAgent, "genesis").payload,
+        evaluator.sign(ActionType::RegisterAgent, "genesis").payload,
+    ];
+    Blockchain::init(genesis, ConsensusParams::default())
+}
+
+#[test]
+fn genesis_registers_every_role() {
+    let mut validator = SimAgent::from_id("validator", AgentRole::Validator);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let blockchain = chain(&mut validator, &mut evaluator);
+
+    assert_eq!(blockchain.registry.validators().len(), 1);
+    assert_eq!(blockchain.registry.electorate(), 2);
+    assert!(blockchain.verify().is_valid());
+}
+
+#[test]
+fn self_signed_validator_registration_is_refused() {
+    let mut validator = SimAgent::from_id("validator", AgentRole::Validator);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let blockchain = chain(&mut validator, &mut evaluator);
+    let mut intruder = SimAgent::from_id("intruder", AgentRole::Validator);
+
+    let tx = intruder.sign(ActionType::RegisterAgent, "join").payload;
+    let err = blockchain.registry.validate_registration(&tx).unwrap_err();
+
+    assert!(matches!(err, ErrorTypes::Unauthorized(_)), "{:?}", err);
+}
+
+#[test]
+fn proposer_registers_itself() {
+    let mut validator = SimAgent::from_id("validator", AgentRole::Validator);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let blockchain = chain(&mut validator, &mut evaluator);
+    let mut proposer = SimAgent::from_id("proposer", AgentRole::Proposer);
+
+    let tx = proposer.sign(ActionType::RegisterAgent, "join").payload;
+
+    blockchain.registry.validate_registration(&tx).unwrap();
+}
+
+#[test]
+fn validator_admits_an_evaluator() {
+    let mut validator = SimAgent::from_id("validator", AgentRole::Validator);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let blockchain = chain(&mut validator, &mut evaluator);
+    let mut newcomer = SimAgent::from_id("newcomer", AgentRole::Evaluator);
+
+    let tx = newcomer.sign_admitted(validator.admit(&newcomer)).payload;
+
+    blockchain.registry.validate_registration(&tx).unwrap();
+}
+
+#[test]
+fn only_a_validator_admits() {
+    let mut validator = SimAgent::from_id("validator", AgentRole::Validator);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let blockchain = chain(&mut validator, &mut evaluator);
+    let mut newcomer = SimAgent::from_id("newcomer", AgentRole::Validator);
+
+    let tx = newcomer.sign_admitted(evaluator.admit(&newcomer)).payload;
+    let err = blockchain.registry.validate_registration(&tx).unwrap_err();
+
+    assert!(matches!(err, ErrorTypes::Unauthorized(_)), "{:?}", err);
+}
+
+#[test]
+fn admission_is_bound_to_the_role() {
+    let mut validator = SimAgent::from_id("validator", AgentRole::Validator);
+    let mut evaluator = SimAgent::from_id("evaluator", AgentRole::Evaluator);
+    let blockchain = chain(&mut validator, &mut evaluator);
+    let mut newcomer = SimAgent::from_id("newcomer", AgentRole::Evaluator);
+    let admission = validator.admit(&newcomer);
+
+    newcomer.role = AgentRole::Validator;
+    let tx = newcomer.sign_admitted(admission).payload;
+    let err = blockchain.registry.validate_registration(&tx).unwrap_err();
+
+    assert!(matches!(err, ErrorTypes::InvalidSignature(_)), "{:?}", err);
+}
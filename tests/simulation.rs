@@ -0,0 +1,202 @@
+// Randomized consensus scenarios on the deterministic simulator. A failing scenario reports its
+// seed, run it again alone with SIM_SEED=<seed>. SIM_SCENARIOS sets how many seeds are tried.
+
+use no_cap::{
+    sim::{Partition, SimAgent, SimConfig, SimRng, Simulation},
+    types::blockchain::{ActionType, AgentRole},
+    utils::crypto::keypair_from_seed,
+};
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok().map(|value| value.parse().unwrap())
+}
+
+// Everything about a scenario is drawn from its seed: the network, the faults, the agents and
+// when they submit what. Clients talk to one node, which relays to the others, and vote once
+// they submitted the proposal there. Some of the nodes are validators, validators and evaluators
+// both vote.
+async fn scenario(seed: u64) -> Simulation {
+    let mut rng = SimRng::new(seed);
+
+    let mut config = SimConfig::new(seed, rng.range(2, 5) as usize);
+    config.max_delay_ms = rng.range(1, 300);
+    config.drop_per_mille = rng.range(0, 300) as u32;
+    if rng.chance(500) {
+        let from_ms = rng.range(0, 2_000);
+        config.partitions.push(Partition {
+            from_ms,
+            until_ms: from_ms + rng.range(100, 3_000),
+            isolated: (0..config.nodes)
+                .filter(|_| rng.chance(500))
+                .take(config.nodes - 1)
+                .collect(),
+        });
+    }
+
+    let validators = rng.range(1, config.nodes as u64);
+    let evaluators = rng.range(0, 3);
+    let agents = agents(&mut rng, validators, evaluators);
+    let voters: Vec<String> = agents
+        .iter()
+        .filter(|agent| agent.role != AgentRole::Proposer)
+        .map(|agent| agent.id.clone())
+        .collect();
+
+    let entry = rng.range(0, config.nodes as u64 - 1) as usize;
+    let proposals = rng.range(1, 4);
+    let mut sim = Simulation::new(config, agents).await;
+    for proposal in 0..proposals {
+        let hash = format!("proposal_{}", proposal);
+        let proposed_at = rng.range(0, 2_000);
+        sim.submit(
+            proposed_at,
+            entry,
+            "proposer",
+            ActionType::ProposeUpdate,
+            &hash,
+        );
+        for voter in &voters {
+            if rng.chance(200) {
+                continue;
+            }
+            let vote = if rng.chance(750) {
+                ActionType::VoteAccept
+            } else {
+                ActionType::VoteReject
+            };
+            sim.submit(proposed_at + rng.range(0, 2_000), entry, voter, vote, &hash);
+        }
+    }
+
+    sim
+}
+
+// A proposer, `validators` validators and `evaluators` evaluators, with keys drawn from `rng`
+fn agents(rng: &mut SimRng, validators: u64, evaluators: u64) -> Vec<SimAgent> {
+    let mut agents = vec![SimAgent::new(
+        "proposer",
+        AgentRole::Proposer,
+        keypair_from_seed(rng.seed_bytes()),
+    )];
+    for index in 0..validators {
+        agents.push(SimAgent::new(
+            &format!("validator_{}", index),
+            AgentRole::Validator,
+            keypair_from_seed(rng.seed_bytes()),
+        ));
+    }
+    for index in 0..evaluators {
+        agents.push(SimAgent::new(
+            &format!("evaluator_{}", index),
+            AgentRole::Evaluator,
+            keypair_from_seed(rng.seed_bytes()),
+        ));
+    }
+    agents
+}
+
+// Runs every seed in SIM_SEED or 0..SIM_SCENARIOS and fails on the first one that ends in a
+// state `check` turns down, printing its trace
+async fn sweep(check: impl AsyncFn(&Simulation) -> Result<(), String>) {
+    let seeds = match env_u64("SIM_SEED") {
+        Some(seed) => seed..seed + 1,
+        None => 0..env_u64("SIM_SCENARIOS").unwrap_or(200),
+    };
+
+    for seed in seeds {
+        let mut sim = scenario(seed).await;
+        sim.run().await;
+        if let Err(failure) = check(&sim).await {
+            for line in &sim.trace {
+                eprintln!("{}", line);
+            }
+            // the test harness names each test's thread after the test
+            let test = std::thread::current()
+                .name()
+                .unwrap_or_default()
+                .to_string();
+            panic!(
+                "seed {}: {}\nreplay with SIM_SEED={} cargo test --test simulation {} -- --exact",
+                seed, failure, seed, test
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn randomized_scenarios_stay_safe() {
+    sweep(Simulation::check).await;
+}
+
+#[tokio::test]
+async fn randomized_scenarios_converge() {
+    sweep(Simulation::converged).await;
+}
+
+#[tokio::test]
+async fn same_seed_replays_the_same_run() {
+    let mut first = scenario(7).await;
+    first.run().await;
+    let mut second = scenario(7).await;
+    second.run().await;
+    let mut other = scenario(8).await;
+    other.run().await;
+
+    assert!(!first.trace.is_empty());
+    assert_eq!(first.trace, second.trace);
+    assert_ne!(first.trace, other.trace);
+    assert_eq!(first.heights().await, second.heights().await);
+}
+
+#[tokio::test]
+async fn votes_sent_before_their_proposal_are_rejected() {
+    let mut sim = Simulation::new(SimConfig::new(1, 3), agents(&mut SimRng::new(0), 3, 0)).await;
+    sim.submit(0, 0, "validator_0", ActionType::VoteAccept, "early");
+    sim.submit(10, 0, "validator_1", ActionType::VoteAccept, "early");
+    sim.submit(500, 0, "proposer", ActionType::ProposeUpdate, "early");
+    sim.run().await;
+
+    sim.check().await.unwrap();
+    sim.converged().await.unwrap();
+    assert_eq!(sim.heights().await, vec![0, 0, 0]);
+    let rejected: Vec<(&str, &str)> = sim
+        .rejected
+        .iter()
+        .map(|(tx, err)| (tx.agent_id.as_str(), err.code()))
+        .collect();
+    assert_eq!(
+        rejected,
+        vec![
+            ("validator_0", "unknown_proposal"),
+            ("validator_1", "unknown_proposal")
+        ]
+    );
+    assert!(sim
+        .trace
+        .iter()
+        .any(|line| line.contains("submit validator_0 VoteAccept early to 0: unknown_proposal")));
+}
+
+#[tokio::test]
+async fn partitioned_node_catches_up_once_healed() {
+    // the three validators left are more than 2/3 of four, so they commit without the fourth
+    let mut config = SimConfig::new(2, 4);
+    config.partitions.push(Partition {
+        from_ms: 0,
+        until_ms: 3_000,
+        isolated: vec![3],
+    });
+    let mut sim = Simulation::new(config, agents(&mut SimRng::new(0), 4, 0)).await;
+    sim.submit(100, 0, "proposer", ActionType::ProposeUpdate, "cut_off");
+    sim.submit(200, 0, "validator_0", ActionType::VoteAccept, "cut_off");
+    sim.submit(300, 0, "validator_1", ActionType::VoteAccept, "cut_off");
+    sim.submit(400, 0, "validator_2", ActionType::VoteAccept, "cut_off");
+
+    sim.run_until(2_500).await;
+    assert_eq!(sim.heights().await, vec![1, 1, 1, 0]);
+
+    sim.run().await;
+    sim.check().await.unwrap();
+    sim.converged().await.unwrap();
+    assert_eq!(sim.heights().await, vec![1, 1, 1, 1]);
+}
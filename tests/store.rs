@@ -0,0 +1,131 @@
+// Recovery of the block store after a crash. Only a record a write never finished may be cut off,
+// anything else that does not read back is corruption and has to be reported.
+
+use chrono::{DateTime, Duration};
+use no_cap::blockchain::{block::Block, consensus::ConsensusParams, store::BlockStore};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+// An empty directory of its own for every test
+fn store_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("no_cap-store-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn segment(dir: &Path) -> PathBuf {
+    dir.join("segment-000000.dat")
+}
+
+// A store holding a genesis block and `count` empty blocks after it
+fn filled(dir: &Path, count: u32) {
+    let (mut store, _) = BlockStore::open(dir).unwrap();
+    let mut prev = Block::init(Vec::new(), ConsensusParams::default());
+    store.append(&prev).unwrap();
+    for index in 1..=count {
+        let block = Block::new(
+            index,
+            0,
+            prev.hash.clone().unwrap(),
+            String::new(),
+            Vec::new(),
+            DateTime::UNIX_EPOCH + Duration::seconds(index as i64),
+        );
+        store.append(&block).unwrap();
+        prev = block;
+    }
+}
+
+fn append_raw(dir: &Path, bytes: &[u8]) {
+    let mut file = OpenOptions::new().append(true).open(segment(dir)).unwrap();
+    file.write_all(bytes).unwrap();
+}
+
+// A record whose checksum matches `payload`
+fn record(payload: &[u8]) -> Vec<u8> {
+    let mut record = (payload.len() as u32).to_be_bytes().to_vec();
+    record.extend_from_slice(&Sha256::digest(payload));
+    record.extend_from_slice(payload);
+    record
+}
+
+#[test]
+fn torn_final_record_is_cut_off() {
+    let dir = store_dir("torn");
+    filled(&dir, 2);
+    let intact_len = fs::metadata(segment(&dir)).unwrap().len();
+    let torn = record(b"{\"index\": 3");
+    append_raw(&dir, &torn[..torn.len() - 4]);
+
+    let (store, blocks) = BlockStore::open(&dir).unwrap();
+
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(store.height(), 3);
+    assert_eq!(fs::metadata(segment(&dir)).unwrap().len(), intact_len);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn scan_reports_a_torn_record_without_cutting_it_off() {
+    let dir = store_dir("scan");
+    filled(&dir, 2);
+    let intact_len = fs::metadata(segment(&dir)).unwrap().len();
+    append_raw(&dir, &[0, 0]);
+
+    let (blocks, torn) = BlockStore::scan(&dir).unwrap();
+
+    let torn = torn.unwrap();
+    assert_eq!(blocks.len(), 3);
+    assert_eq!((torn.segment, torn.offset, torn.height), (0, intact_len, 3));
+    assert_eq!(fs::metadata(segment(&dir)).unwrap().len(), intact_len + 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn final_record_failing_its_checksum_is_cut_off() {
+    let dir = store_dir("checksum");
+    filled(&dir, 1);
+    let mut broken = record(b"{}");
+    let last = broken.len() - 1;
+    broken[last] ^= 0xff;
+    append_raw(&dir, &broken);
+
+    let (_, blocks) = BlockStore::open(&dir).unwrap();
+
+    assert_eq!(blocks.len(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn intact_record_that_does_not_parse_is_an_error() {
+    let dir = store_dir("unparsable");
+    filled(&dir, 1);
+    append_raw(&dir, &record(b"not a block"));
+    let len = fs::metadata(segment(&dir)).unwrap().len();
+
+    let err = BlockStore::open(&dir).unwrap_err();
+
+    assert!(err.to_string().contains("block 2"), "{}", err);
+    assert_eq!(fs::metadata(segment(&dir)).unwrap().len(), len);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn corrupt_record_before_the_end_is_an_error() {
+    let dir = store_dir("middle");
+    filled(&dir, 2);
+    let mut bytes = fs::read(segment(&dir)).unwrap();
+    // inside the payload of the genesis block
+    bytes[40] ^= 0xff;
+    fs::write(segment(&dir), &bytes).unwrap();
+
+    let err = BlockStore::open(&dir).unwrap_err();
+
+    assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+    assert_eq!(fs::read(segment(&dir)).unwrap(), bytes);
+    fs::remove_dir_all(&dir).unwrap();
+}